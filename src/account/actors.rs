@@ -1,4 +1,7 @@
-use crate::account::types::Account;
+use crate::account::types::{
+    activity_id, Account, Activity, FillType, NonTradeActivity, TradeActivity,
+};
+use crate::calendar;
 use crate::errors::Result;
 use crate::exchange::TradeFill;
 use crate::position::{
@@ -7,18 +10,63 @@ use crate::position::{
 };
 use actix::dev::{MessageResponse, ResponseChannel};
 use actix::prelude::*;
+use chrono::{DateTime, TimeZone, Utc};
 use tracing::{debug, trace};
 use tracing_futures::Instrument;
 
 #[derive(Debug)]
 pub struct AccountManager {
     pub account: Account,
+    pub activities: Vec<Activity>,
 }
 
 impl Default for AccountManager {
     fn default() -> Self {
         Self {
-            account: Account::new(100000.0),
+            account: Account::new(100000.0, Utc.timestamp_nanos(0)),
+            activities: vec![],
+        }
+    }
+}
+
+impl AccountManager {
+    /// Records the fill itself and every fee charged on it.
+    fn record_activities(&mut self, tf: &TradeFill) {
+        let qty = tf.qty.abs() as u32;
+        let cum_qty = tf.order.filled_qty + qty;
+        let fill_type = if cum_qty < tf.order.qty {
+            FillType::PartialFill
+        } else {
+            FillType::Fill
+        };
+        self.activities.push(Activity::Fill(TradeActivity {
+            id: activity_id(tf.time),
+            transaction_time: tf.time,
+            fill_type,
+            price: tf.price,
+            qty,
+            side: tf.order.side.clone(),
+            symbol: tf.order.symbol.clone(),
+            leaves_qty: tf.order.qty.saturating_sub(cum_qty),
+            order_id: tf.order.id,
+            cum_qty,
+        }));
+        let fees = vec![
+            (tf.fees.commission, "Commission"),
+            (tf.fees.sec_fee, "SEC Section 31 fee"),
+            (tf.fees.finra_taf, "FINRA TAF"),
+        ];
+        for (amount, description) in fees {
+            if amount == 0.0 {
+                continue;
+            }
+            self.activities.push(Activity::Fee(NonTradeActivity {
+                id: activity_id(tf.time),
+                date: calendar::session_date(tf.time.timestamp_nanos()),
+                net_amount: -amount,
+                symbol: Some(tf.order.symbol.clone()),
+                description: description.to_string(),
+            }));
         }
     }
 }
@@ -68,7 +116,8 @@ impl Handler<TradeFill> for AccountManager {
     fn handle(&mut self, tf: TradeFill, _ctx: &mut Context<Self>) -> Self::Result {
         trace!("Received TradeFill");
         let cost_basis = tf.price * tf.qty as f64;
-        self.account.cash -= cost_basis;
+        self.account.cash -= cost_basis + tf.fees.total();
+        self.record_activities(&tf);
         self.account.initial_margin += 0.5 * cost_basis;
         self.account.daytrade_count += 1;
         self.account.daytrading_buying_power =
@@ -116,15 +165,83 @@ impl Handler<TradeFill> for AccountManager {
 
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
-pub struct SetCash(pub f64);
+pub struct SetCash {
+    pub cash: f64,
+    /// When the account is opened.
+    pub time: DateTime<Utc>,
+}
 
 impl Handler<SetCash> for AccountManager {
     type Result = ();
 
     #[tracing::instrument(name = "AccountManager: Handle<SetCash>", skip(self, _ctx))]
-    fn handle(&mut self, cash: SetCash, _ctx: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: SetCash, _ctx: &mut Context<Self>) -> Self::Result {
         trace!("Received SetCash");
-        debug!("Updating cash: {}", &cash.0);
-        self.account = Account::new(cash.0);
+        debug!("Updating cash: {}", &msg.cash);
+        self.account = Account::new(msg.cash, msg.time);
+        self.activities.clear();
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "Vec<Activity>")]
+pub struct GetActivities;
+
+impl Handler<GetActivities> for AccountManager {
+    type Result = MessageResult<GetActivities>;
+
+    #[tracing::instrument(name = "AccountManager: Handle<GetActivities>", skip(self, _msg, _ctx))]
+    fn handle(&mut self, _msg: GetActivities, _ctx: &mut Context<Self>) -> Self::Result {
+        trace!("Received GetActivities");
+        MessageResult(self.activities.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asset::types::Asset;
+    use crate::fees::Fees;
+    use crate::order::types::{Order, OrderIntent, Side};
+
+    #[actix_rt::test]
+    async fn fees_reduce_cash() {
+        let manager = AccountManager::default().start();
+        manager
+            .send(SetCash {
+                cash: 10_000.0,
+                time: Utc::now(),
+            })
+            .await
+            .unwrap();
+        let order = Order::from_intent(
+            &OrderIntent::new("AAPL").qty(10).side(Side::Sell),
+            &Asset::from_symbol("AAPL"),
+            Utc::now(),
+        )
+        .unwrap();
+        let fill = TradeFill {
+            time: Utc::now(),
+            qty: -10,
+            price: 100.0,
+            order,
+            fees: Fees {
+                commission: 1.0,
+                sec_fee: 0.03,
+                finra_taf: 0.01,
+            },
+        };
+        manager.send(fill).await.unwrap().unwrap();
+        let account = manager.send(GetAccount).await.unwrap();
+        assert!((account.cash - (11_000.0 - 1.04)).abs() < 1e-9);
+        let activities = manager.send(GetActivities).await.unwrap();
+        assert_eq!(activities.len(), 4);
+        match &activities[0] {
+            Activity::Fill(fill) => {
+                assert_eq!(fill.fill_type, FillType::Fill);
+                assert_eq!(fill.cum_qty, 10);
+            }
+            _ => panic!("Expected the fill first"),
+        }
     }
 }
@@ -1,6 +1,6 @@
 use crate::account::{
-    actors::{AccountManager, GetAccount},
-    types::Account,
+    actors::{AccountManager, GetAccount, GetActivities},
+    types::{Account, Activity},
 };
 use crate::errors::{Error, Result};
 use actix::registry::SystemService;
@@ -16,3 +16,11 @@ pub async fn get_account() -> Result<Account> {
         .await
         .map_err(|e| Error::from(e))
 }
+
+#[tracing::instrument]
+pub async fn get_activities() -> Result<Vec<Activity>> {
+    AccountManager::from_registry()
+        .send(GetActivities {})
+        .await
+        .map_err(|e| Error::from(e))
+}
@@ -1,5 +1,6 @@
+use crate::order::types::Side;
 use crate::utils::{from_str, to_string};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -60,7 +61,7 @@ pub struct Account {
 }
 
 impl Account {
-    pub fn new(cash: f64) -> Self {
+    pub fn new(cash: f64, created_at: DateTime<Utc>) -> Self {
         let (multiplier, daytrading_buying_power, regt_buying_power) = if cash < 2000.0 {
             (1.0, 0.0, cash)
         } else if cash < 25000.0 {
@@ -81,7 +82,7 @@ impl Account {
             trading_blocked: false,
             transfers_blocked: false,
             account_blocked: false,
-            created_at: Utc::now(),
+            created_at,
             shorting_enabled: cash >= 2000.0,
             long_market_value: 0.0,
             short_market_value: 0.0,
@@ -100,6 +101,54 @@ impl Account {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FillType {
+    Fill,
+    PartialFill,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradeActivity {
+    pub id: String,
+    pub transaction_time: DateTime<Utc>,
+    #[serde(rename = "type")]
+    pub fill_type: FillType,
+    #[serde(serialize_with = "to_string", deserialize_with = "from_str")]
+    pub price: f64,
+    #[serde(serialize_with = "to_string", deserialize_with = "from_str")]
+    pub qty: u32,
+    pub side: Side,
+    pub symbol: String,
+    #[serde(serialize_with = "to_string", deserialize_with = "from_str")]
+    pub leaves_qty: u32,
+    pub order_id: Uuid,
+    #[serde(serialize_with = "to_string", deserialize_with = "from_str")]
+    pub cum_qty: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NonTradeActivity {
+    pub id: String,
+    pub date: NaiveDate,
+    #[serde(serialize_with = "to_string", deserialize_with = "from_str")]
+    pub net_amount: f64,
+    pub symbol: Option<String>,
+    pub description: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "activity_type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Activity {
+    Fill(TradeActivity),
+    Fee(NonTradeActivity),
+}
+
+/// Activity ids sort by time, like Alpaca's.
+pub fn activity_id(time: DateTime<Utc>) -> String {
+    format!("{}::{}", time.format("%Y%m%d%H%M%S%3f"), Uuid::new_v4())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -140,15 +189,54 @@ mod test {
         let _serialized = serde_json::to_string(&deserialized).unwrap();
     }
 
+    #[test]
+    fn activities() {
+        let json = r#"
+        [
+            {
+                "activity_type": "FILL",
+                "cum_qty": "1",
+                "id": "20190524113406977::8efc7b9a-8b2b-4000-9955-d36e7db0df74",
+                "leaves_qty": "0",
+                "price": "1.63",
+                "qty": "1",
+                "side": "buy",
+                "symbol": "LPCN",
+                "transaction_time": "2019-05-24T15:34:06.977Z",
+                "order_id": "904837e3-3b76-47ec-b432-046db621571b",
+                "type": "fill"
+            },
+            {
+                "activity_type": "FEE",
+                "id": "20190801011955195::5f596936-6f23-4cef-bdf1-3806aae57dbf",
+                "date": "2019-08-01",
+                "net_amount": "-0.02",
+                "symbol": "T",
+                "description": "SEC Section 31 fee"
+            }
+        ]
+        "#;
+        let deserialized: Vec<Activity> = serde_json::from_str(json).unwrap();
+        match &deserialized[0] {
+            Activity::Fill(fill) => assert_eq!(fill.fill_type, FillType::Fill),
+            _ => panic!("Expected a fill"),
+        }
+        match &deserialized[1] {
+            Activity::Fee(fee) => assert_eq!(fee.net_amount, -0.02),
+            _ => panic!("Expected a fee"),
+        }
+        let _serialized = serde_json::to_string(&deserialized).unwrap();
+    }
+
     #[test]
     fn initialization() {
-        let cash_account = Account::new(1000.0);
+        let cash_account = Account::new(1000.0, Utc::now());
         assert_eq!(cash_account.multiplier, 1.0);
         assert!(!cash_account.shorting_enabled);
         assert_eq!(cash_account.daytrading_buying_power, 0.0);
         assert_eq!(cash_account.regt_buying_power, cash_account.cash);
 
-        let low_equity_account = Account::new(20_000.0);
+        let low_equity_account = Account::new(20_000.0, Utc::now());
         assert_eq!(low_equity_account.multiplier, 2.0);
         assert!(low_equity_account.shorting_enabled);
         assert_eq!(low_equity_account.daytrading_buying_power, 0.0);
@@ -157,7 +245,7 @@ mod test {
             2.0 * low_equity_account.cash
         );
 
-        let high_equity_account = Account::new(200_000.0);
+        let high_equity_account = Account::new(200_000.0, Utc::now());
         assert_eq!(high_equity_account.multiplier, 4.0);
         assert!(high_equity_account.shorting_enabled);
         assert_eq!(
@@ -0,0 +1,133 @@
+use bdays::{calendars::us::USNYSE, HolidayCalendar};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::America::New_York;
+
+pub fn is_trading_day(date: NaiveDate) -> bool {
+    USNYSE.is_bday(date)
+}
+
+/// Whether NYSE closes at 13:00 on `date`, as it does on the day before Independence Day, the day
+/// after Thanksgiving and Christmas Eve when those are trading days.
+pub fn is_early_close(date: NaiveDate) -> bool {
+    if !is_trading_day(date) {
+        return false;
+    }
+    match (date.month(), date.day()) {
+        (7, 3) | (12, 24) => true,
+        // Thanksgiving is the fourth Thursday of November
+        (11, day) => date.weekday() == Weekday::Fri && (23..=29).contains(&day),
+        _ => false,
+    }
+}
+
+/// The first trading day strictly after `date`.
+pub fn next_trading_day(date: NaiveDate) -> NaiveDate {
+    let mut next = date + Duration::days(1);
+    while !is_trading_day(next) {
+        next = next + Duration::days(1);
+    }
+    next
+}
+
+/// The New York calendar date of a nanosecond UNIX timestamp.
+pub fn session_date(timestamp: i64) -> NaiveDate {
+    Utc.timestamp_nanos(timestamp)
+        .with_timezone(&New_York)
+        .naive_local()
+        .date()
+}
+
+/// `time` on `date` in New York, converted to UTC.
+pub fn new_york_time(date: NaiveDate, time: NaiveTime) -> DateTime<Utc> {
+    New_York
+        .from_local_datetime(&date.and_time(time))
+        .single()
+        .expect("Session times are never skipped or repeated by DST transitions")
+        .with_timezone(&Utc)
+}
+
+/// Open and close of the regular trading session on `date`, 9:30 to 16:00 New York time, or to
+/// 13:00 on early close days.
+pub fn regular_session(date: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+    let close = if is_early_close(date) { 13 } else { 16 };
+    (
+        new_york_time(date, NaiveTime::from_hms(9, 30, 0)),
+        new_york_time(date, NaiveTime::from_hms(close, 0, 0)),
+    )
+}
+
+/// Start of the pre-market and end of the post-market session on `date`, 4:00 to 20:00 New York
+/// time, or to 17:00 on early close days.
+pub fn extended_session(date: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+    let close = if is_early_close(date) { 17 } else { 20 };
+    (
+        new_york_time(date, NaiveTime::from_hms(4, 0, 0)),
+        new_york_time(date, NaiveTime::from_hms(close, 0, 0)),
+    )
+}
+
+/// NYSE trading days between `start` and `end`, both inclusive.
+pub fn trading_days(start: NaiveDate, end: NaiveDate) -> Vec<NaiveDate> {
+    let mut days = vec![];
+    let mut date = start;
+    while date <= end {
+        if is_trading_day(date) {
+            days.push(date);
+        }
+        date = date + Duration::days(1);
+    }
+    days
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn session_date_is_new_york_local() {
+        // 2020-09-18 20:30 EDT is already the 19th in UTC
+        let timestamp = 1_600_475_400_000_000_000;
+        assert_eq!(session_date(timestamp), NaiveDate::from_ymd(2020, 9, 18));
+    }
+
+    #[test]
+    fn regular_session_follows_dst() {
+        let (open, close) = regular_session(NaiveDate::from_ymd(2020, 9, 18));
+        assert_eq!(open, Utc.ymd(2020, 9, 18).and_hms(13, 30, 0));
+        assert_eq!(close, Utc.ymd(2020, 9, 18).and_hms(20, 0, 0));
+        let (open, _) = regular_session(NaiveDate::from_ymd(2020, 12, 18));
+        assert_eq!(open, Utc.ymd(2020, 12, 18).and_hms(14, 30, 0));
+    }
+
+    #[test]
+    fn early_closes() {
+        let thanksgiving_friday = NaiveDate::from_ymd(2020, 11, 27);
+        assert!(is_early_close(thanksgiving_friday));
+        let (_, close) = regular_session(thanksgiving_friday);
+        assert_eq!(close, Utc.ymd(2020, 11, 27).and_hms(18, 0, 0));
+        let (_, post_close) = extended_session(thanksgiving_friday);
+        assert_eq!(post_close, Utc.ymd(2020, 11, 27).and_hms(22, 0, 0));
+        assert!(is_early_close(NaiveDate::from_ymd(2020, 12, 24)));
+        assert!(is_early_close(NaiveDate::from_ymd(2019, 7, 3)));
+        // Observed Independence Day
+        assert!(!is_early_close(NaiveDate::from_ymd(2020, 7, 3)));
+        assert!(!is_early_close(NaiveDate::from_ymd(2020, 11, 20)));
+    }
+
+    #[test]
+    fn skips_weekends_and_holidays() {
+        let days = trading_days(
+            NaiveDate::from_ymd(2020, 7, 1),
+            NaiveDate::from_ymd(2020, 7, 7),
+        );
+        assert_eq!(
+            days,
+            vec![
+                NaiveDate::from_ymd(2020, 7, 1),
+                NaiveDate::from_ymd(2020, 7, 2),
+                NaiveDate::from_ymd(2020, 7, 6),
+                NaiveDate::from_ymd(2020, 7, 7),
+            ]
+        );
+    }
+}
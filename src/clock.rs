@@ -1,15 +1,44 @@
-use chrono::{DateTime, Utc};
+use crate::calendar;
+use crate::errors::Result;
+use crate::market::{Bar, Quote, Trade};
+use actix::prelude::*;
+use chrono::{DateTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
+use tracing::debug;
 
-pub fn get_clock() -> Clock {
-    // TODO: Make this dynamically pull from exchange
+/// The current simulated time, i.e. the time of the last replayed market event.
+pub async fn now() -> Result<DateTime<Utc>> {
+    Ok(SimulationClock::from_registry().send(GetTime).await?)
+}
+
+pub async fn get_clock() -> Result<Clock> {
+    Ok(clock_at(now().await?))
+}
+
+pub fn clock_at(now: DateTime<Utc>) -> Clock {
+    let today = calendar::session_date(now.timestamp_nanos());
+    let (open, close) = calendar::regular_session(today);
+    let is_trading_day = calendar::is_trading_day(today);
+    let (next_day_open, next_day_close) =
+        calendar::regular_session(calendar::next_trading_day(today));
+    let next_open = if is_trading_day && now < open {
+        open
+    } else {
+        next_day_open
+    };
+    let next_close = if is_trading_day && now < close {
+        close
+    } else {
+        next_day_close
+    };
     Clock {
-        timestamp: Utc::now(),
-        is_open: true,
-        next_open: Utc::now(),
-        next_close: Utc::now(),
+        timestamp: now,
+        is_open: is_trading_day && open <= now && now < close,
+        next_open,
+        next_close,
     }
 }
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Clock {
     pub timestamp: DateTime<Utc>,
@@ -17,3 +46,119 @@ pub struct Clock {
     pub next_open: DateTime<Utc>,
     pub next_close: DateTime<Utc>,
 }
+
+/// Keeps the simulated time in step with the replay. It should be the first subscriber of the
+/// market so that the other actors see the time of the event they are handling.
+pub struct SimulationClock {
+    now: DateTime<Utc>,
+}
+
+impl Default for SimulationClock {
+    fn default() -> Self {
+        SimulationClock {
+            now: Utc.timestamp_nanos(0),
+        }
+    }
+}
+
+impl SimulationClock {
+    fn set(&mut self, timestamp: i64) {
+        self.now = Utc.timestamp_nanos(timestamp);
+    }
+}
+
+impl Actor for SimulationClock {
+    type Context = Context<Self>;
+}
+
+impl actix::Supervised for SimulationClock {}
+
+impl SystemService for SimulationClock {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        debug!("SimulationClock service started");
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetTime(pub DateTime<Utc>);
+
+impl Handler<SetTime> for SimulationClock {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetTime, _ctx: &mut Context<Self>) {
+        self.set(msg.0.timestamp_nanos());
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "DateTime<Utc>")]
+pub struct GetTime;
+
+impl Handler<GetTime> for SimulationClock {
+    type Result = MessageResult<GetTime>;
+
+    fn handle(&mut self, _msg: GetTime, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.now)
+    }
+}
+
+impl Handler<Trade> for SimulationClock {
+    type Result = ();
+
+    fn handle(&mut self, msg: Trade, _ctx: &mut Context<Self>) {
+        self.set(msg.timestamp);
+    }
+}
+
+impl Handler<Quote> for SimulationClock {
+    type Result = ();
+
+    fn handle(&mut self, msg: Quote, _ctx: &mut Context<Self>) {
+        self.set(msg.timestamp);
+    }
+}
+
+impl Handler<Bar> for SimulationClock {
+    type Result = ();
+
+    fn handle(&mut self, msg: Bar, _ctx: &mut Context<Self>) {
+        self.set(msg.timestamp);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn during_session() {
+        let clock = clock_at(Utc.ymd(2020, 9, 18).and_hms(15, 0, 0));
+        assert!(clock.is_open);
+        assert_eq!(clock.next_open, Utc.ymd(2020, 9, 21).and_hms(13, 30, 0));
+        assert_eq!(clock.next_close, Utc.ymd(2020, 9, 18).and_hms(20, 0, 0));
+    }
+
+    #[test]
+    fn before_open() {
+        let clock = clock_at(Utc.ymd(2020, 9, 18).and_hms(12, 0, 0));
+        assert!(!clock.is_open);
+        assert_eq!(clock.next_open, Utc.ymd(2020, 9, 18).and_hms(13, 30, 0));
+    }
+
+    #[actix_rt::test]
+    async fn follows_the_replay() {
+        let clock = SimulationClock::default().start();
+        let time = Utc.ymd(2020, 9, 18).and_hms(15, 0, 0);
+        clock.send(SetTime(time)).await.unwrap();
+        assert_eq!(clock.send(GetTime).await.unwrap(), time);
+    }
+
+    #[test]
+    fn weekend() {
+        let clock = clock_at(Utc.ymd(2020, 9, 19).and_hms(15, 0, 0));
+        assert!(!clock.is_open);
+        assert_eq!(clock.next_open, Utc.ymd(2020, 9, 21).and_hms(13, 30, 0));
+        assert_eq!(clock.next_close, Utc.ymd(2020, 9, 21).and_hms(20, 0, 0));
+    }
+}
@@ -0,0 +1,341 @@
+use crate::errors::{Error, Result};
+use crate::exchange::QueueModel;
+use crate::fees::FeeSchedule;
+use crate::latency::Latency;
+use crate::market::{synthetic::SyntheticConfig, MarketSource, Sessions, Speed, TradeFilter};
+use crate::slippage::SlippageConfig;
+use chrono::NaiveDate;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// Config file read when `--config` is not given, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "acticks.toml";
+
+/// Settings of a simulation run. Every setting can be given in a TOML file and overridden on the
+/// command line.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub cash: f64,
+    pub symbols: Vec<String>,
+    pub speed: Speed,
+    pub bind_address: String,
+    pub source: MarketSource,
+    /// Whether NBBO quotes are replayed along with the trades, for the sources that have them.
+    pub quotes: bool,
+    /// Which replayed trades reach the exchange and the positions.
+    pub filter: TradeFilter,
+    /// Where the market reads its data from, or caches it for Polygon. Each market falls back to
+    /// its own environment variable when unset.
+    pub data_directory: Option<PathBuf>,
+    pub synthetic: SyntheticConfig,
+    /// Largest fraction of each replayed trade that resting orders may fill against, unlimited
+    /// if unset.
+    pub participation: Option<f64>,
+    pub queue_model: QueueModel,
+    pub slippage: SlippageConfig,
+    /// Simulated delay between accepting an order and its arrival at the exchange.
+    pub latency: Latency,
+    pub fees: FeeSchedule,
+    pub start_date: NaiveDate,
+    /// Last session to replay, the start date if unset.
+    pub end_date: Option<NaiveDate>,
+    /// Tracing filter directives, e.g. `debug` or `acticks=trace`. Falls back to `RUST_LOG` and
+    /// then `info` when unset.
+    pub log_level: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cash: 1_000_000.0,
+            symbols: vec!["AAPL".into()],
+            speed: Speed::Multiplier(60),
+            bind_address: "127.0.0.1:8000".into(),
+            source: MarketSource::default(),
+            quotes: false,
+            filter: TradeFilter::default(),
+            data_directory: None,
+            synthetic: SyntheticConfig::default(),
+            participation: None,
+            queue_model: QueueModel::default(),
+            slippage: SlippageConfig::default(),
+            latency: Latency::default(),
+            fees: FeeSchedule::default(),
+            start_date: NaiveDate::from_ymd(2020, 9, 18),
+            end_date: None,
+            log_level: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file named by `args`, or the default one if present, applies the command
+    /// line overrides on top and checks the result.
+    pub fn load(args: Args) -> Result<Self> {
+        let path = args.config.clone().or_else(|| {
+            let default = PathBuf::from(DEFAULT_CONFIG_FILE);
+            if default.exists() {
+                Some(default)
+            } else {
+                None
+            }
+        });
+        let config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        let config = config.with_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings the simulation cannot run with.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |what: &str| Err(Error::InvalidConfig(what.to_string()));
+        if !self.cash.is_finite() || self.cash < 0.0 {
+            return invalid("cash must not be negative");
+        }
+        if let Some(participation) = self.participation {
+            if participation.is_nan() || participation <= 0.0 || participation > 1.0 {
+                return invalid("participation must be above 0 and at most 1");
+            }
+        }
+        let latencies = [self.latency.delay_ms, self.latency.jitter_ms];
+        if latencies.iter().any(|ms| !ms.is_finite() || *ms < 0.0) {
+            return invalid("latency must not be negative");
+        }
+        if self.end_date.map_or(false, |end| end < self.start_date) {
+            return invalid("end_date must not be before start_date");
+        }
+        if self.source == MarketSource::Synthetic {
+            self.synthetic.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    fn with_args(mut self, args: Args) -> Self {
+        if let Some(cash) = args.cash {
+            self.cash = cash;
+        }
+        if let Some(symbols) = args.symbols {
+            self.symbols = symbols;
+        }
+        if let Some(speed) = args.speed {
+            self.speed = speed;
+        }
+        if let Some(bind_address) = args.bind_address {
+            self.bind_address = bind_address;
+        }
+        if let Some(source) = args.source {
+            self.source = source;
+        }
+        if args.quotes {
+            self.quotes = true;
+        }
+        if let Some(data_directory) = args.data_directory {
+            self.data_directory = Some(data_directory);
+        }
+        if let Some(participation) = args.participation {
+            self.participation = Some(participation);
+        }
+        if let Some(queue_model) = args.queue_model {
+            self.queue_model = queue_model;
+        }
+        if let Some(bps) = args.slippage_bps {
+            self.slippage = SlippageConfig::FixedBps { bps };
+        }
+        if let Some(delay_ms) = args.latency_ms {
+            self.latency.delay_ms = delay_ms;
+        }
+        if let Some(jitter_ms) = args.jitter_ms {
+            self.latency.jitter_ms = jitter_ms;
+        }
+        if let Some(per_share) = args.commission_per_share {
+            self.fees.per_share = per_share;
+        }
+        if let Some(start_date) = args.start_date {
+            self.start_date = start_date;
+        }
+        if let Some(end_date) = args.end_date {
+            self.end_date = Some(end_date);
+        }
+        if let Some(log_level) = args.log_level {
+            self.log_level = Some(log_level);
+        }
+        self
+    }
+
+    pub fn sessions(&self) -> Sessions {
+        Sessions::Range {
+            start: self.start_date,
+            end: self.end_date.unwrap_or(self.start_date),
+        }
+    }
+}
+
+#[derive(Debug, Default, StructOpt)]
+#[structopt(name = "acticks", about = "A backtesting brokerage server")]
+pub struct Args {
+    /// TOML config file, `acticks.toml` is read if present and no file is given
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// Starting cash of the account
+    #[structopt(long)]
+    pub cash: Option<f64>,
+    /// Comma separated symbols to replay
+    #[structopt(long, use_delimiter = true)]
+    pub symbols: Option<Vec<String>>,
+    /// Replay speed, either a multiple of real time or `max`
+    #[structopt(long)]
+    pub speed: Option<Speed>,
+    /// Address the API listens on
+    #[structopt(long = "bind")]
+    pub bind_address: Option<String>,
+    /// Market data source: files, bars, synthetic or polygon
+    #[structopt(long)]
+    pub source: Option<MarketSource>,
+    /// Replay NBBO quotes along with the trades
+    #[structopt(long)]
+    pub quotes: bool,
+    /// Directory the market data is read from
+    #[structopt(long, parse(from_os_str))]
+    pub data_directory: Option<PathBuf>,
+    /// Largest fraction of each replayed trade that resting orders may fill against
+    #[structopt(long)]
+    pub participation: Option<f64>,
+    /// Limit order matching, either `touch` or the queue size assumed when there is no quote
+    #[structopt(long = "queue")]
+    pub queue_model: Option<QueueModel>,
+    /// Fixed slippage in basis points, other models are only available in the config file
+    #[structopt(long)]
+    pub slippage_bps: Option<f64>,
+    /// Simulated order latency in milliseconds
+    #[structopt(long)]
+    pub latency_ms: Option<f64>,
+    /// Upper bound of a random extra latency in milliseconds
+    #[structopt(long)]
+    pub jitter_ms: Option<f64>,
+    /// Commission per share, other fees are only available in the config file
+    #[structopt(long)]
+    pub commission_per_share: Option<f64>,
+    /// First session to replay, as YYYY-MM-DD
+    #[structopt(long = "start")]
+    pub start_date: Option<NaiveDate>,
+    /// Last session to replay, as YYYY-MM-DD
+    #[structopt(long = "end")]
+    pub end_date: Option<NaiveDate>,
+    /// Tracing filter, e.g. `debug`
+    #[structopt(long)]
+    pub log_level: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::market::Tape;
+
+    #[test]
+    fn parse_file() {
+        let config: Config = toml::from_str(
+            r#"
+            cash = 50000.0
+            symbols = ["AAPL", "TSLA"]
+            speed = "max"
+            source = "synthetic"
+            quotes = true
+            start_date = "2020-09-14"
+            end_date = "2020-09-18"
+
+            [synthetic]
+            seed = 7
+
+            [filter]
+            tapes = [1, 3]
+            exchange_ids = [11]
+
+            [slippage]
+            model = "square_root"
+            coefficient = 0.1
+
+            [latency]
+            delay_ms = 5.0
+
+            [fees]
+            per_order = 1.0
+            finra_taf_max = 5.95
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.cash, 50_000.0);
+        assert_eq!(config.speed, Speed::Max);
+        assert_eq!(config.source, MarketSource::Synthetic);
+        assert!(config.quotes);
+        assert_eq!(config.filter.tapes, Some(vec![Tape::A, Tape::C]));
+        assert_eq!(config.filter.exchange_ids, Some(vec![11]));
+        assert_eq!(
+            config.filter.excluded_conditions,
+            TradeFilter::default().excluded_conditions
+        );
+        assert_eq!(config.synthetic.seed, 7);
+        assert_eq!(
+            config.slippage,
+            SlippageConfig::SquareRoot { coefficient: 0.1 }
+        );
+        assert_eq!(config.latency.delay_ms, 5.0);
+        assert_eq!(config.latency.jitter_ms, 0.0);
+        assert_eq!(config.fees.per_order, 1.0);
+        assert_eq!(config.fees.finra_taf_max, Some(5.95));
+        assert_eq!(config.bind_address, "127.0.0.1:8000");
+        assert_eq!(config.sessions().dates().len(), 5);
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Config::default().validate().is_ok());
+        let invalid = vec![
+            "participation = 1.5",
+            "participation = -0.2",
+            "cash = -1.0",
+            "latency = { delay_ms = -5.0 }",
+            "start_date = \"2020-09-18\"\nend_date = \"2020-09-14\"",
+            "source = \"synthetic\"\nsynthetic = { volatility = -0.2 }",
+        ];
+        for toml in invalid {
+            let config: Config = toml::from_str(toml).unwrap();
+            assert!(
+                matches!(config.validate(), Err(Error::InvalidConfig(_))),
+                "{} should be invalid",
+                toml
+            );
+        }
+    }
+
+    #[test]
+    fn args_override_file() {
+        let config: Config = toml::from_str("cash = 50000.0\nspeed = { multiplier = 10 }").unwrap();
+        assert_eq!(config.speed, Speed::Multiplier(10));
+        let args = Args::from_iter(vec![
+            "acticks",
+            "--speed",
+            "120",
+            "--symbols",
+            "MSFT,TSLA",
+            "--start",
+            "2020-09-21",
+        ]);
+        let config = config.with_args(args);
+        assert_eq!(config.cash, 50_000.0);
+        assert_eq!(config.speed, Speed::Multiplier(120));
+        assert_eq!(config.symbols, vec!["MSFT", "TSLA"]);
+        assert_eq!(
+            config.sessions().dates(),
+            vec![NaiveDate::from_ymd(2020, 9, 21)]
+        );
+    }
+}
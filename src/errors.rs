@@ -9,9 +9,33 @@ pub enum Error {
     #[error("target order is no longer cancelable")]
     Uncancelable,
 
+    #[error("invalid order: {0}")]
+    InvalidOrder(String),
+
     #[error("tried to get uninitialize price")]
     UninitializedPrice,
 
+    #[error("no replay is running")]
+    ReplayNotRunning,
+
+    #[error("unknown market data source: {0}")]
+    UnknownMarket(String),
+
+    #[error("invalid replay speed: {0}")]
+    InvalidSpeed(String),
+
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("invalid queue model: {0}")]
+    InvalidQueueModel(String),
+
+    #[error("invalid trade condition: {0}")]
+    InvalidCondition(String),
+
+    #[error("market data out of timestamp order: {0} follows {1}")]
+    UnsortedData(i64, i64),
+
     #[error("Missing environment variable: {0}")]
     MissingEnv(#[from] std::env::VarError),
 
@@ -19,6 +43,19 @@ pub enum Error {
     #[error("Reqwest error: {0}")]
     Reqwest(#[from] reqwest::Error),
 
+    #[cfg(feature = "polygon")]
+    #[error("Polygon error: {0}")]
+    Polygon(String),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("TOML error: {0}")]
+    Toml(#[from] toml::de::Error),
+
     #[error("Serde error: {0}")]
     Serde(#[from] serde_json::Error),
 
@@ -41,7 +78,8 @@ impl error::ResponseError for Error {
     fn status_code(&self) -> StatusCode {
         match *self {
             Error::NotFound => StatusCode::NOT_FOUND,
-            Error::Uncancelable => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Uncancelable | Error::InvalidOrder(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::ReplayNotRunning => StatusCode::CONFLICT,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
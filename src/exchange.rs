@@ -1,16 +1,27 @@
 use crate::account::actors::AccountManager;
 use crate::asset::types::Asset;
+use crate::calendar;
+use crate::clock;
 use crate::errors::{Error, Result};
-use crate::market::Trade;
+use crate::fees::{FeeSchedule, Fees};
+use crate::latency::{Latency, LatencySampler};
+use crate::market::{Bar, Quote, Trade};
 use crate::order::{
-    actors::OrderManager,
-    types::{Order, OrderType, Side},
+    actors::{
+        ActivateOrder, CancelOrder, CancelOrders, ExpireOrder, OrderManager, RejectOrder,
+        SetInFlight, UpdateOrderType,
+    },
+    types::{Order, OrderStatus, OrderType, Side, TimeInForce},
 };
 use crate::position::actors::PositionManager;
+use crate::slippage::{Execution, NoSlippage, SlippageModel, SymbolStats};
 use actix::prelude::*;
-use chrono::{DateTime, Utc};
-use std::collections::HashMap;
-use tracing::debug;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use tracing::{debug, warn};
+use uuid::Uuid;
 
 #[derive(Clone, Debug, Message)]
 #[rtype(result = "Result<()>")]
@@ -19,21 +30,145 @@ pub struct TradeFill {
     pub qty: i32,
     pub price: f64,
     pub order: Order,
+    pub fees: Fees,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MarketStatus {
     PreOpen,
     Open,
     PostClose,
-    Maintenance,
     Closed,
 }
 
+impl MarketStatus {
+    /// The session the market is in at `time`: pre-market from 4:00, regular hours from 9:30 and
+    /// post-market from 16:00 until 20:00 New York time on trading days, or from 13:00 until 17:00
+    /// on early close days, closed otherwise.
+    pub fn at(time: DateTime<Utc>) -> Self {
+        let date = calendar::session_date(time.timestamp_nanos());
+        if !calendar::is_trading_day(date) {
+            return MarketStatus::Closed;
+        }
+        let (pre_open, post_close) = calendar::extended_session(date);
+        let (open, close) = calendar::regular_session(date);
+        if time < pre_open || time >= post_close {
+            MarketStatus::Closed
+        } else if time < open {
+            MarketStatus::PreOpen
+        } else if time < close {
+            MarketStatus::Open
+        } else {
+            MarketStatus::PostClose
+        }
+    }
+
+    /// Whether `o` may trade now. Extended hours orders also trade before the open and after the
+    /// close.
+    pub fn accepts(&self, o: &Order) -> bool {
+        match (self, o.extended_hours) {
+            (MarketStatus::Open, _)
+            | (MarketStatus::PreOpen, true)
+            | (MarketStatus::PostClose, true) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The order in which the prices of a bar are assumed to have traded.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntrabarPath {
+    OpenHighLowClose,
+    OpenLowHighClose,
+    /// Visits whichever of the high and low is closest to the open first.
+    NearestExtremeFirst,
+}
+
+impl Default for IntrabarPath {
+    fn default() -> Self {
+        IntrabarPath::NearestExtremeFirst
+    }
+}
+
+impl IntrabarPath {
+    pub fn prices(&self, bar: &Bar) -> [f64; 4] {
+        let high_first = match self {
+            IntrabarPath::OpenHighLowClose => true,
+            IntrabarPath::OpenLowHighClose => false,
+            IntrabarPath::NearestExtremeFirst => bar.high - bar.open <= bar.open - bar.low,
+        };
+        if high_first {
+            [bar.open, bar.high, bar.low, bar.close]
+        } else {
+            [bar.open, bar.low, bar.high, bar.close]
+        }
+    }
+}
+
+/// How resting limit orders are matched against replayed trades.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(tag = "model", rename_all = "snake_case")]
+pub enum QueueModel {
+    /// Orders fill as soon as a trade touches their limit price.
+    Touch,
+    /// Orders join the back of the queue at their price, behind the size displayed there when
+    /// they arrive, or `default_size` without a quote to go by. They only fill once that much
+    /// volume has traded at or through their price.
+    Queue { default_size: u32 },
+}
+
+impl Default for QueueModel {
+    fn default() -> Self {
+        QueueModel::Touch
+    }
+}
+
+impl FromStr for QueueModel {
+    type Err = Error;
+
+    /// Parses `touch`, or the default queue size such as `500`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "touch" => Ok(QueueModel::Touch),
+            _ => s
+                .parse()
+                .map(|default_size| QueueModel::Queue { default_size })
+                .map_err(|_| Error::InvalidQueueModel(s.to_string())),
+        }
+    }
+}
+
 pub struct Exchange {
     pub stored_orders: Vec<Order>,
+    /// The simulated time, that of the last market event.
+    pub now: DateTime<Utc>,
     pub market_status: MarketStatus,
     pub assets: Vec<Asset>,
     pub prices: HashMap<String, f64>,
+    pub quotes: HashMap<String, Quote>,
+    pub intrabar_path: IntrabarPath,
+    /// Largest fraction of a replayed trade's size, or of the size displayed by a quote, that
+    /// resting orders may fill against it.
+    /// Resting orders fill completely when unset.
+    pub participation: Option<f64>,
+    pub queue_model: QueueModel,
+    pub slippage: Box<dyn SlippageModel + Send>,
+    pub stats: HashMap<String, SymbolStats>,
+    pub fees: FeeSchedule,
+    pub latency: LatencySampler,
+    /// Orders on their way to the exchange, with the simulated time they arrive at.
+    pub in_flight: Vec<(i64, Order)>,
+    /// Orders waiting for a session they may trade in.
+    pub queued_orders: Vec<Order>,
+    /// Estimated shares ahead of each resting limit order under `QueueModel::Queue`.
+    pub queue_ahead: HashMap<Uuid, u32>,
+    /// The session close each stored DAY order expires at, in nanoseconds.
+    pub expiries: HashMap<Uuid, i64>,
+    /// The other order of each one-cancels-other pair.
+    pub siblings: HashMap<Uuid, Uuid>,
+    /// The legs released by partially filled orders, which grow as their parent keeps filling.
+    pub children: HashMap<Uuid, Vec<Uuid>>,
 }
 
 impl Actor for Exchange {
@@ -56,7 +191,36 @@ impl Handler<TransmitOrder> for Exchange {
     type Result = Result<Option<TradeFill>>;
 
     fn handle(&mut self, msg: TransmitOrder, _ctx: &mut Context<Self>) -> Self::Result {
-        self.transmit_order(msg.0)
+        let delay = self.latency.sample();
+        if delay > 0 {
+            OrderManager::from_registry().do_send(SetInFlight {
+                id: msg.0.id,
+                in_flight: true,
+                time: self.now,
+            });
+            self.delay(msg.0, self.now.timestamp_nanos() + delay);
+            return Ok(None);
+        }
+        let fill = self.transmit_order(msg.0)?;
+        self.link_orders(fill.iter());
+        Ok(fill)
+    }
+}
+
+/// Tells the exchange that the replay is over. Orders still on their way to it are rejected: no
+/// later event will ever let them arrive, and transmitting them at the last prices would fill
+/// them after the market data has ended.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EndReplay;
+
+impl Handler<EndReplay> for Exchange {
+    type Result = ();
+
+    fn handle(&mut self, _msg: EndReplay, _ctx: &mut Context<Self>) -> Self::Result {
+        for id in self.reject_in_flight() {
+            OrderManager::from_registry().do_send(RejectOrder { id, time: self.now });
+        }
     }
 }
 
@@ -66,12 +230,22 @@ pub struct SetAssets {
     pub assets: Vec<Asset>,
 }
 
+impl Handler<clock::SetTime> for Exchange {
+    type Result = ();
+
+    fn handle(&mut self, msg: clock::SetTime, _ctx: &mut Context<Self>) -> Self::Result {
+        self.set_time(msg.0.timestamp_nanos());
+    }
+}
+
 impl Handler<SetAssets> for Exchange {
     type Result = ();
 
     fn handle(&mut self, msg: SetAssets, _ctx: &mut Context<Self>) -> Self::Result {
         self.assets = msg.assets;
         self.prices = HashMap::new();
+        self.quotes = HashMap::new();
+        self.stats = HashMap::new();
     }
 }
 
@@ -79,13 +253,164 @@ impl Handler<Trade> for Exchange {
     type Result = ResponseFuture<()>;
 
     fn handle(&mut self, msg: Trade, _ctx: &mut Context<Self>) -> Self::Result {
-        let trades = self.update_price(&msg.symbol, msg.price);
-        let fut = async {
-            for trade in trades {
-                update_from_fill(&trade).await.unwrap()
-            }
-        };
-        Box::pin(fut)
+        let expired = self.expire_orders(msg.timestamp);
+        self.set_time(msg.timestamp);
+        let (arrived, mut fills) = self.release_orders(msg.timestamp);
+        fills.extend(self.update_trade(&msg));
+        self.link_orders(fills.iter());
+        settle(expired, arrived, fills, self.now)
+    }
+}
+
+/// Tells the order manager which orders expired and which reached the exchange at `time`, then
+/// books `fills` in order.
+fn settle(
+    expired: Vec<(Uuid, i64)>,
+    arrived: Vec<Uuid>,
+    fills: Vec<TradeFill>,
+    time: DateTime<Utc>,
+) -> ResponseFuture<()> {
+    for (id, close) in expired {
+        OrderManager::from_registry().do_send(ExpireOrder {
+            id,
+            time: Utc.timestamp_nanos(close),
+        });
+    }
+    for id in arrived {
+        OrderManager::from_registry().do_send(SetInFlight {
+            id,
+            in_flight: false,
+            time,
+        });
+    }
+    Box::pin(async move {
+        for fill in fills {
+            update_from_fill(&fill).await.unwrap()
+        }
+    })
+}
+
+impl Handler<CancelOrder> for Exchange {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: CancelOrder, _ctx: &mut Context<Self>) -> Self::Result {
+        self.cancel(&msg.id);
+        Ok(())
+    }
+}
+
+impl Handler<CancelOrders> for Exchange {
+    type Result = ();
+
+    fn handle(&mut self, _msg: CancelOrders, _ctx: &mut Context<Self>) -> Self::Result {
+        let ids: Vec<Uuid> = self
+            .stored_orders
+            .iter()
+            .chain(self.in_flight.iter().map(|(_, o)| o))
+            .chain(self.queued_orders.iter())
+            .map(|o| o.id)
+            .collect();
+        for id in ids {
+            self.cancel(&id);
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetLatency(pub Latency);
+
+impl Handler<SetLatency> for Exchange {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetLatency, _ctx: &mut Context<Self>) -> Self::Result {
+        self.latency = LatencySampler::new(msg.0);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetIntrabarPath(pub IntrabarPath);
+
+impl Handler<SetIntrabarPath> for Exchange {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetIntrabarPath, _ctx: &mut Context<Self>) -> Self::Result {
+        self.intrabar_path = msg.0;
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetParticipation(pub Option<f64>);
+
+impl Handler<SetParticipation> for Exchange {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetParticipation, _ctx: &mut Context<Self>) -> Self::Result {
+        self.participation = msg.0;
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetQueueModel(pub QueueModel);
+
+impl Handler<SetQueueModel> for Exchange {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetQueueModel, _ctx: &mut Context<Self>) -> Self::Result {
+        self.queue_model = msg.0;
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetSlippage(pub Box<dyn SlippageModel + Send>);
+
+impl Handler<SetSlippage> for Exchange {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetSlippage, _ctx: &mut Context<Self>) -> Self::Result {
+        self.slippage = msg.0;
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetFees(pub FeeSchedule);
+
+impl Handler<SetFees> for Exchange {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetFees, _ctx: &mut Context<Self>) -> Self::Result {
+        self.fees = msg.0;
+    }
+}
+
+impl Handler<Bar> for Exchange {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: Bar, _ctx: &mut Context<Self>) -> Self::Result {
+        let expired = self.expire_orders(msg.timestamp);
+        self.set_time(msg.timestamp);
+        let (arrived, mut fills) = self.release_orders(msg.timestamp);
+        fills.extend(self.update_bar(&msg));
+        self.link_orders(fills.iter());
+        settle(expired, arrived, fills, self.now)
+    }
+}
+
+impl Handler<Quote> for Exchange {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: Quote, _ctx: &mut Context<Self>) -> Self::Result {
+        let expired = self.expire_orders(msg.timestamp);
+        self.set_time(msg.timestamp);
+        let (arrived, mut fills) = self.release_orders(msg.timestamp);
+        fills.extend(self.update_quote(msg));
+        self.link_orders(fills.iter());
+        settle(expired, arrived, fills, self.now)
     }
 }
 
@@ -97,54 +422,208 @@ impl Exchange {
         });
         Self {
             stored_orders: vec![],
-            market_status: MarketStatus::Open,
+            now: Utc.timestamp_nanos(0),
+            market_status: MarketStatus::Closed,
             assets,
             prices,
+            quotes: HashMap::new(),
+            intrabar_path: IntrabarPath::default(),
+            participation: None,
+            queue_model: QueueModel::default(),
+            slippage: Box::new(NoSlippage),
+            stats: HashMap::new(),
+            fees: FeeSchedule::default(),
+            latency: LatencySampler::default(),
+            in_flight: vec![],
+            queued_orders: vec![],
+            queue_ahead: HashMap::new(),
+            expiries: HashMap::new(),
+            siblings: HashMap::new(),
+            children: HashMap::new(),
         }
     }
 
-    pub fn transmit_order(&mut self, o: Order) -> Result<Option<TradeFill>> {
-        match (&self.market_status, o.extended_hours) {
-            (MarketStatus::Open, _)
-            | (MarketStatus::PreOpen, true)
-            | (MarketStatus::PostClose, true) => match o.order_type {
-                OrderType::Market => {
-                    let price = *self.get_price(&o.symbol)?;
-                    Ok(Some(self.execute(o, price)))
-                }
+    /// Executes, stores or queues `o`, and then the legs working alongside it, which are left
+    /// out when `o` itself fails.
+    pub fn transmit_order(&mut self, mut o: Order) -> Result<Option<TradeFill>> {
+        let (id, legs) = (o.id, o.active_legs());
+        if let Ok(price) = self.get_price(&o.symbol).map(|p| *p) {
+            if o.trail(price) {
+                notify_trail(&o);
+            }
+        }
+        let transmitted = if self.market_status.accepts(&o) {
+            match (&o.time_in_force, &o.order_type) {
+                (TimeInForce::IOC, _) | (TimeInForce::FOK, _) => Ok(self.execute_immediately(o)),
+                (_, OrderType::Market) => self
+                    .get_execution_price(&o.symbol, &o.side)
+                    .map(|price| Some(self.execute(o, price))),
                 _ => self.execute_or_store(o),
-            },
-            (MarketStatus::Maintenance, _) => todo!(),
+            }
+        } else {
+            match o.time_in_force {
+                TimeInForce::IOC | TimeInForce::FOK => kill(&o, self.now),
+                _ => self.queued_orders.push(o),
+            }
+            Ok(None)
+        };
+        if transmitted.is_ok() {
+            self.store_active_legs(id, legs);
+        }
+        transmitted
+    }
+
+    /// Fills as much of an IOC or FOK order as the market offers right now, which is the size
+    /// displayed at the opposite side of the quote when quotes are replayed. What an IOC order
+    /// cannot fill is canceled, and a FOK order that cannot fill completely is rejected. Neither
+    /// is ever stored.
+    fn execute_immediately(&mut self, mut o: Order) -> Option<TradeFill> {
+        let remaining = o.qty - o.filled_qty;
+        let price = self.get_execution_price(&o.symbol, &o.side).ok();
+        if let Some(price) = price {
+            trigger_stop(&mut o, price, price);
+        }
+        let qty = match price {
+            Some(price) if is_marketable(&o, price) => {
+                match (self.quotes.get(&o.symbol), &o.side) {
+                    (Some(quote), Side::Buy) => remaining.min(quote.ask_size),
+                    (Some(quote), Side::Sell) => remaining.min(quote.bid_size),
+                    (None, _) => remaining,
+                }
+            }
+            _ => 0,
+        };
+        match (price, &o.time_in_force) {
+            (Some(price), TimeInForce::IOC) if qty > 0 => Some(self.execute_qty(o, price, qty)),
+            (Some(price), TimeInForce::FOK) if qty == remaining => Some(self.execute(o, price)),
             _ => {
-                self.store(o);
-                Ok(None)
+                kill(&o, self.now);
+                None
+            }
+        }
+    }
+
+    /// Holds `o` back until the replay reaches `arrival`.
+    pub fn delay(&mut self, o: Order, arrival: i64) {
+        self.in_flight.push((arrival, o));
+    }
+
+    /// Transmits the orders that have arrived by `timestamp`, in order of arrival, at the prices
+    /// in effect before the event at `timestamp`. Returns the ids of the arrived orders along
+    /// with their immediate fills.
+    pub fn release_orders(&mut self, timestamp: i64) -> (Vec<Uuid>, Vec<TradeFill>) {
+        if self.in_flight.is_empty() {
+            return (vec![], vec![]);
+        }
+        let mut arrived: Vec<(i64, Order)> = self
+            .in_flight
+            .drain_filter(|(arrival, _)| *arrival <= timestamp)
+            .collect();
+        arrived.sort_by_key(|(arrival, _)| *arrival);
+        let mut ids = vec![];
+        let mut fills = vec![];
+        for (_, o) in arrived {
+            ids.push(o.id);
+            match self.transmit_order(o.clone()) {
+                Ok(Some(fill)) => fills.push(fill),
+                Ok(None) => (),
+                Err(e) => {
+                    warn!("Storing order {} until it can be priced: {}", o.id, e);
+                    self.store_active_legs(o.id, o.active_legs());
+                    self.store(o);
+                }
             }
         }
+        (ids, fills)
+    }
+
+    /// Drops the orders that have not arrived yet, and returns their ids.
+    pub fn reject_in_flight(&mut self) -> Vec<Uuid> {
+        let ids = self.in_flight.drain(..).map(|(_, o)| o.id).collect();
+        self.forget_removed_orders();
+        ids
     }
 
     pub fn is_open(&self) -> bool {
-        true
+        self.market_status == MarketStatus::Open
     }
 
     pub fn market_status(&self) -> MarketStatus {
-        todo!()
+        self.market_status
     }
 
+    /// Moves the simulated time to `timestamp`, and the market into the session it is in then.
+    /// Stored orders that may not trade in the new session are queued, and queued orders that may
+    /// are stored, so that they work from the event at `timestamp` on.
+    pub fn set_time(&mut self, timestamp: i64) {
+        self.now = Utc.timestamp_nanos(timestamp);
+        let status = MarketStatus::at(Utc.timestamp_nanos(timestamp));
+        if status == self.market_status {
+            return;
+        }
+        debug!(
+            "Market status changed from {:?} to {:?}",
+            self.market_status, status
+        );
+        self.market_status = status;
+        let waiting: Vec<Order> = self
+            .stored_orders
+            .drain_filter(|o| !status.accepts(o))
+            .collect();
+        let ready: Vec<Order> = self
+            .queued_orders
+            .drain_filter(|o| status.accepts(o))
+            .collect();
+        self.queued_orders.extend(waiting);
+        for o in ready {
+            self.store(o);
+        }
+        self.forget_removed_orders();
+    }
+
+    /// Fills whatever is left of `order` at `price`.
     pub fn execute(&self, order: Order, price: f64) -> TradeFill {
+        let qty = order.qty - order.filled_qty;
+        self.execute_qty(order, price, qty)
+    }
+
+    /// Fills `qty` shares of `order` at `price` moved by the slippage model, but never beyond
+    /// the order's limit price.
+    pub fn execute_qty(&self, order: Order, price: f64, qty: u32) -> TradeFill {
+        let execution = Execution {
+            side: &order.side,
+            qty,
+            price,
+            quote: self.quotes.get(&order.symbol),
+            stats: self.stats.get(&order.symbol),
+        };
+        let slipped = self.slippage.fill_price(&execution);
+        let price = match (&order.order_type, &order.side) {
+            (OrderType::Limit { limit_price }, Side::Buy)
+            | (OrderType::StopLimit { limit_price, .. }, Side::Buy) => slipped.min(*limit_price),
+            (OrderType::Limit { limit_price }, Side::Sell)
+            | (OrderType::StopLimit { limit_price, .. }, Side::Sell) => slipped.max(*limit_price),
+            _ => slipped,
+        };
+        let fees = self
+            .fees
+            .fees(&order.side, qty, price, order.filled_qty == 0);
         let qty = match order.side {
-            Side::Buy => order.qty as i32,
-            Side::Sell => -(order.qty as i32),
+            Side::Buy => qty as i32,
+            Side::Sell => -(qty as i32),
         };
         TradeFill {
-            time: Utc::now(),
+            time: self.now,
             qty,
             price,
             order,
+            fees,
         }
     }
 
-    pub fn execute_or_store(&mut self, o: Order) -> Result<Option<TradeFill>> {
-        let price = *self.get_price(&o.symbol)?;
+    pub fn execute_or_store(&mut self, mut o: Order) -> Result<Option<TradeFill>> {
+        let price = self.get_execution_price(&o.symbol, &o.side)?;
+        trigger_stop(&mut o, price, price);
         if is_marketable(&o, price) {
             Ok(Some(self.execute(o, price)))
         } else {
@@ -154,20 +633,394 @@ impl Exchange {
     }
 
     pub fn store(&mut self, o: Order) {
+        if let Some(ahead) = self.initial_queue(&o) {
+            self.queue_ahead.insert(o.id, ahead);
+        }
+        if o.time_in_force == TimeInForce::DAY {
+            self.expiries
+                .insert(o.id, day_order_expiry(&o, self.now).timestamp_nanos());
+        }
         self.stored_orders.push(o);
     }
 
+    /// Stores `legs`, which work alongside `parent`, linked to it so that either one filling
+    /// cancels the other.
+    fn store_active_legs(&mut self, parent: Uuid, legs: Vec<Order>) {
+        for leg in legs {
+            self.link(parent, leg.id);
+            self.store_or_queue(leg);
+        }
+    }
+
+    /// Stores `o` if it may trade in the current session, and queues it for its session
+    /// otherwise.
+    fn store_or_queue(&mut self, o: Order) {
+        if self.market_status.accepts(&o) {
+            self.store(o);
+        } else {
+            self.queued_orders.push(o);
+        }
+    }
+
+    fn link(&mut self, a: Uuid, b: Uuid) {
+        self.siblings.insert(a, b);
+        self.siblings.insert(b, a);
+    }
+
+    /// Applies the order class rules to `fills`: a fill on one order of a one-cancels-other pair
+    /// cancels the other, and the first fill of an order releases its held legs for the quantity
+    /// filled, which then cancel each other. Released legs grow with every later fill of their
+    /// parent. They are stored, or queued until their session, so they work from the next market
+    /// event.
+    pub fn link_orders<'a>(&mut self, fills: impl Iterator<Item = &'a TradeFill>) {
+        for fill in fills {
+            let order = &fill.order;
+            self.cancel_sibling(&order.id);
+            let filled = order.filled_qty + fill.qty.abs() as u32;
+            let time = self.now;
+            if let Some(children) = self.children.get(&order.id) {
+                for leg in self
+                    .stored_orders
+                    .iter_mut()
+                    .chain(self.queued_orders.iter_mut())
+                    .filter(|o| children.contains(&o.id))
+                {
+                    leg.qty = filled;
+                    OrderManager::from_registry().do_send(ActivateOrder {
+                        id: leg.id,
+                        qty: filled,
+                        time,
+                    });
+                }
+            } else {
+                let held = order.held_legs();
+                if let [first, second] = held.as_slice() {
+                    self.link(first.id, second.id);
+                }
+                if !held.is_empty() && filled < order.qty {
+                    let ids = held.iter().map(|leg| leg.id).collect();
+                    self.children.insert(order.id, ids);
+                }
+                for mut leg in held {
+                    leg.status = OrderStatus::New;
+                    leg.qty = filled;
+                    leg.submitted_at = Some(time);
+                    leg.updated_at = Some(time);
+                    OrderManager::from_registry().do_send(ActivateOrder {
+                        id: leg.id,
+                        qty: filled,
+                        time,
+                    });
+                    self.store_or_queue(leg);
+                }
+            }
+            if filled >= order.qty {
+                self.children.remove(&order.id);
+            }
+        }
+    }
+
+    /// Drops an order that was canceled before it filled, along with the other order of its
+    /// one-cancels-other pair.
+    pub fn cancel(&mut self, id: &Uuid) {
+        self.stored_orders.retain(|o| &o.id != id);
+        self.in_flight.retain(|(_, o)| &o.id != id);
+        self.queued_orders.retain(|o| &o.id != id);
+        self.cancel_sibling(id);
+        self.forget_removed_orders();
+    }
+
+    /// Drops the other order of the one-cancels-other pair `id` belongs to, if any, and has the
+    /// order manager cancel it.
+    fn cancel_sibling(&mut self, id: &Uuid) {
+        if let Some(sibling) = self.siblings.remove(id) {
+            self.siblings.remove(&sibling);
+            self.stored_orders.retain(|o| o.id != sibling);
+            self.queued_orders.retain(|o| o.id != sibling);
+            self.forget_removed_orders();
+            OrderManager::from_registry().do_send(CancelOrder {
+                id: sibling,
+                time: self.now,
+            });
+        }
+    }
+
+    /// Drops the DAY orders whose session closed by `timestamp`, and returns them along with the
+    /// close they expired at.
+    pub fn expire_orders(&mut self, timestamp: i64) -> Vec<(Uuid, i64)> {
+        if self.expiries.values().all(|close| *close > timestamp) {
+            return vec![];
+        }
+        let expired: Vec<(Uuid, i64)> = self
+            .expiries
+            .iter()
+            .filter(|(_, close)| **close <= timestamp)
+            .map(|(id, close)| (*id, *close))
+            .collect();
+        let ids: HashSet<Uuid> = expired.iter().map(|(id, _)| *id).collect();
+        self.stored_orders.retain(|o| !ids.contains(&o.id));
+        self.forget_removed_orders();
+        expired
+    }
+
+    /// The shares estimated to be ahead of a limit order joining the book.
+    fn initial_queue(&self, o: &Order) -> Option<u32> {
+        let default_size = match self.queue_model {
+            QueueModel::Touch => return None,
+            QueueModel::Queue { default_size } => default_size,
+        };
+        let limit_price = match o.order_type {
+            OrderType::Limit { limit_price } => limit_price,
+            _ => return None,
+        };
+        let ahead = match (self.quotes.get(&o.symbol), &o.side) {
+            // Improving on the best price starts a new level
+            (Some(quote), Side::Buy) if limit_price > quote.bid_price => 0,
+            (Some(quote), Side::Sell) if limit_price < quote.ask_price => 0,
+            // Joining the best price, or resting behind it with at least as much ahead
+            (Some(quote), Side::Buy) => quote.bid_size,
+            (Some(quote), Side::Sell) => quote.ask_size,
+            (None, _) => default_size,
+        };
+        Some(ahead)
+    }
+
+    /// Counts `size` shares traded at or through the price of `o` against its queue, and returns
+    /// how many of them were left over for `o` itself.
+    fn advance_queue(&mut self, o: &Order, size: u32) -> u32 {
+        match self.queue_ahead.get_mut(&o.id) {
+            Some(ahead) => {
+                let consumed = (*ahead).min(size);
+                *ahead -= consumed;
+                size - consumed
+            }
+            None => u32::MAX,
+        }
+    }
+
+    /// Drops the queue positions, expiries and released legs of orders that are no longer
+    /// stored or queued.
+    fn forget_removed_orders(&mut self) {
+        if self.queue_ahead.is_empty() && self.expiries.is_empty() && self.children.is_empty() {
+            return;
+        }
+        let known: HashSet<Uuid> = self
+            .stored_orders
+            .iter()
+            .chain(self.queued_orders.iter())
+            .map(|o| o.id)
+            .collect();
+        self.queue_ahead.retain(|id, _| known.contains(id));
+        self.expiries.retain(|id, _| known.contains(id));
+        self.children.retain(|id, _| known.contains(id));
+    }
+
     pub fn get_price(&self, symbol: &str) -> Result<&f64> {
         self.prices
             .get(symbol)
             .ok_or_else(|| Error::UninitializedPrice)
     }
 
+    /// The price an incoming order on `side` would execute at: the opposite side of the latest
+    /// quote when quotes are replayed, the last trade price otherwise.
+    pub fn get_execution_price(&self, symbol: &str, side: &Side) -> Result<f64> {
+        match (self.quotes.get(symbol), side) {
+            (Some(quote), Side::Buy) => Ok(quote.ask_price),
+            (Some(quote), Side::Sell) => Ok(quote.bid_price),
+            (None, _) => self.get_price(symbol).map(|p| *p),
+        }
+    }
+
+    /// Records the latest quote and fills the stored orders it makes marketable. Market orders
+    /// fill at the opposite side of the quote, limit orders at their limit price. Stop orders are
+    /// only triggered by trades. Under the queue model, a quote only touching the price of a limit
+    /// order does not fill it while shares are still ahead of it. The orders share the size
+    /// displayed on the side they take, or the allowed fraction of it with a participation limit,
+    /// and partially filled orders stay stored for later quotes and trades.
+    pub fn update_quote(&mut self, quote: Quote) -> Vec<TradeFill> {
+        let participation = self.participation;
+        let available = |size: u32| match participation {
+            Some(participation) => (size as f64 * participation).floor() as u32,
+            None => size,
+        };
+        let mut ask_size = available(quote.ask_size);
+        let mut bid_size = available(quote.bid_size);
+        let mut fills = vec![];
+        let mut stored_orders = vec![];
+        for mut o in std::mem::take(&mut self.stored_orders) {
+            let ahead = self.queue_ahead.get(&o.id).copied().unwrap_or(0);
+            let price = match quote_fill_price(&o, &quote, ahead) {
+                Some(price) if o.symbol == quote.symbol => price,
+                _ => {
+                    stored_orders.push(o);
+                    continue;
+                }
+            };
+            let displayed = match o.side {
+                Side::Buy => &mut ask_size,
+                Side::Sell => &mut bid_size,
+            };
+            let qty = (o.qty - o.filled_qty).min(*displayed);
+            if qty == 0 {
+                stored_orders.push(o);
+                continue;
+            }
+            *displayed -= qty;
+            fills.push(self.execute_qty(o.clone(), price, qty));
+            o.filled_qty += qty;
+            if o.filled_qty < o.qty {
+                stored_orders.push(o);
+            }
+        }
+        self.stored_orders = stored_orders;
+        // Nobody can be ahead of an order but the size displayed at its price
+        for o in self
+            .stored_orders
+            .iter()
+            .filter(|o| o.symbol == quote.symbol)
+        {
+            if let (OrderType::Limit { limit_price }, Some(ahead)) =
+                (&o.order_type, self.queue_ahead.get_mut(&o.id))
+            {
+                let displayed = match o.side {
+                    Side::Buy if (limit_price - quote.bid_price).abs() < 1e-9 => quote.bid_size,
+                    Side::Sell if (limit_price - quote.ask_price).abs() < 1e-9 => quote.ask_size,
+                    _ => continue,
+                };
+                *ahead = (*ahead).min(displayed);
+            }
+        }
+        self.quotes.insert(quote.symbol.clone(), quote);
+        self.forget_removed_orders();
+        fills
+    }
+
+    /// Walks the bar along the configured intrabar path. Orders marketable at the open fill at
+    /// the open, and orders triggered later in the bar fill at the price that triggered them,
+    /// since the path passes through every price between its points.
+    pub fn update_bar(&mut self, bar: &Bar) -> Vec<TradeFill> {
+        self.stats.entry(bar.symbol.clone()).or_default().record(
+            bar.timestamp,
+            bar.close,
+            bar.volume as u64,
+        );
+        let path = self.intrabar_path.prices(bar);
+        let mut fills = self.update_price(&bar.symbol, path[0]);
+        for segment in path.windows(2) {
+            let (from, to) = (segment[0], segment[1]);
+            self.prices.insert(bar.symbol.clone(), to);
+            // Stops triggered along the segment work from the price that triggered them
+            let triggered = self.trigger_stops(&bar.symbol, from, to);
+            let start = |o: &Order| triggered.get(&o.id).copied().unwrap_or(from);
+            let marketable_orders: Vec<Order> = self
+                .stored_orders
+                .drain_filter(|o| {
+                    o.symbol == bar.symbol && (is_marketable(o, start(o)) || is_marketable(o, to))
+                })
+                .collect();
+            for o in marketable_orders {
+                let from = start(&o);
+                let price = if is_marketable(&o, from) {
+                    from
+                } else {
+                    trigger_price(&o)
+                        .unwrap_or(from)
+                        .max(from.min(to))
+                        .min(from.max(to))
+                };
+                fills.push(self.execute(o, price));
+            }
+            self.trail(&bar.symbol, to);
+        }
+        self.forget_removed_orders();
+        fills
+    }
+
+    /// Records the price of a replayed trade and fills the stored orders it makes marketable, in
+    /// the order they were stored. With a participation limit the orders share the allowed
+    /// fraction of the trade's size, and partially filled orders stay stored for later trades.
+    /// Under the queue model, limit orders only get the part of the trade left after their queue,
+    /// and orders resting at the same price share what is left in the order they were stored.
+    pub fn update_trade(&mut self, trade: &Trade) -> Vec<TradeFill> {
+        let mut available = self
+            .participation
+            .map(|participation| (trade.size as f64 * participation).floor() as u32);
+        self.prices.insert(trade.symbol.clone(), trade.price);
+        self.trail(&trade.symbol, trade.price);
+        self.trigger_stops(&trade.symbol, trade.price, trade.price);
+        self.stats.entry(trade.symbol.clone()).or_default().record(
+            trade.timestamp,
+            trade.price,
+            trade.size as u64,
+        );
+        let mut fills = vec![];
+        let mut stored_orders = vec![];
+        // The volume of the trade not yet taken by the queues and orders at each limit price
+        let mut volume_left: HashMap<u64, u32> = HashMap::new();
+        for mut o in std::mem::take(&mut self.stored_orders) {
+            if o.symbol != trade.symbol || !is_marketable(&o, trade.price) {
+                stored_orders.push(o);
+                continue;
+            }
+            let volume = match (&o.order_type, self.queue_ahead.contains_key(&o.id)) {
+                (OrderType::Limit { limit_price }, true) => Some(
+                    volume_left
+                        .entry(limit_price.to_bits())
+                        .or_insert(trade.size),
+                ),
+                _ => None,
+            };
+            let behind_queue = self.advance_queue(&o, volume.as_ref().map_or(trade.size, |v| **v));
+            let qty = (o.qty - o.filled_qty)
+                .min(behind_queue)
+                .min(available.unwrap_or(u32::MAX));
+            if let Some(volume) = volume {
+                *volume = behind_queue - qty;
+            }
+            if qty == 0 {
+                stored_orders.push(o);
+                continue;
+            }
+            if let Some(available) = available.as_mut() {
+                *available -= qty;
+            }
+            fills.push(self.execute_qty(o.clone(), trade.price, qty));
+            o.filled_qty += qty;
+            if o.filled_qty < o.qty {
+                stored_orders.push(o);
+            }
+        }
+        self.stored_orders = stored_orders;
+        self.forget_removed_orders();
+        fills
+    }
+
+    /// Moves the stops of the trailing stop orders in `symbol` with `price`.
+    fn trail(&mut self, symbol: &str, price: f64) {
+        for o in self.stored_orders.iter_mut().filter(|o| o.symbol == symbol) {
+            if o.trail(price) {
+                notify_trail(o);
+            }
+        }
+    }
+
+    /// Triggers the stop orders in `symbol` whose stop the price touched on its way from `from`
+    /// to `to`, and returns the price each of them was triggered at.
+    fn trigger_stops(&mut self, symbol: &str, from: f64, to: f64) -> HashMap<Uuid, f64> {
+        self.stored_orders
+            .iter_mut()
+            .filter(|o| o.symbol == symbol)
+            .filter_map(|o| trigger_stop(o, from, to).map(|price| (o.id, price)))
+            .collect()
+    }
+
     pub fn update_price(&mut self, symbol: &str, price: f64) -> Vec<TradeFill> {
         self.prices
             .entry(symbol.to_string())
             .and_modify(|e| *e = price)
             .or_insert(price);
+        self.trail(symbol, price);
+        self.trigger_stops(symbol, price, price);
         let marketable_orders: Vec<Order> = self
             .stored_orders
             .drain_filter(|o| &o.symbol == symbol && is_marketable(o, price))
@@ -194,26 +1047,729 @@ pub async fn update_from_fill(tf: &TradeFill) -> Result<()> {
     PositionManager::from_registry().send(tf.clone()).await??;
     Ok(())
 }
+
+/// Cancels an IOC order, or rejects a FOK order, that could not fill when it arrived at `time`.
+fn kill(o: &Order, time: DateTime<Utc>) {
+    match o.time_in_force {
+        TimeInForce::FOK => OrderManager::from_registry().do_send(RejectOrder { id: o.id, time }),
+        _ => OrderManager::from_registry().do_send(CancelOrder { id: o.id, time }),
+    }
+}
+
+/// When a DAY order received at `time` expires: the close of the session it was received in,
+/// or of the next session if that one is already over. Extended hours orders last until the end
+/// of the post-market session.
+fn day_order_expiry(o: &Order, time: DateTime<Utc>) -> DateTime<Utc> {
+    let close = |date| {
+        if o.extended_hours {
+            calendar::extended_session(date).1
+        } else {
+            calendar::regular_session(date).1
+        }
+    };
+    let mut date = calendar::session_date(time.timestamp_nanos());
+    if !calendar::is_trading_day(date) || close(date) <= time {
+        date = calendar::next_trading_day(date);
+    }
+    close(date)
+}
+
+/// Shows the order manager where the stop of a trailing stop order is now.
+fn notify_trail(o: &Order) {
+    OrderManager::from_registry().do_send(UpdateOrderType {
+        id: o.id,
+        order_type: o.order_type.clone(),
+    });
+}
+
+/// Turns a stop order into the order it stands for once the price touches its stop on the way
+/// from `from` to `to`: a market order for stops and trailing stops, and a limit order for
+/// stop-limits. The triggered order stays live even if the price moves back through the stop.
+/// Returns the price that triggered it.
+fn trigger_stop(o: &mut Order, from: f64, to: f64) -> Option<f64> {
+    let stop_price = match o.order_type {
+        OrderType::Stop { stop_price }
+        | OrderType::StopLimit { stop_price, .. }
+        | OrderType::TrailingStop {
+            stop_price: Some(stop_price),
+            ..
+        } => stop_price,
+        _ => return None,
+    };
+    let triggered_at = match o.side {
+        Side::Buy if from >= stop_price => from,
+        Side::Buy if to >= stop_price => stop_price,
+        Side::Sell if from <= stop_price => from,
+        Side::Sell if to <= stop_price => stop_price,
+        _ => return None,
+    };
+    o.order_type = match o.order_type {
+        OrderType::StopLimit { limit_price, .. } => OrderType::Limit { limit_price },
+        _ => OrderType::Market,
+    };
+    Some(triggered_at)
+}
+
+/// The price at which a continuously moving market first makes the order marketable.
+fn trigger_price(o: &Order) -> Option<f64> {
+    match &o.order_type {
+        OrderType::Market => None,
+        OrderType::Limit { limit_price } => Some(*limit_price),
+        OrderType::Stop { stop_price } | OrderType::StopLimit { stop_price, .. } => {
+            Some(*stop_price)
+        }
+        OrderType::TrailingStop { stop_price, .. } => *stop_price,
+    }
+}
+
+/// The price a stored order fills at against `quote`, if the quote makes it marketable: the
+/// opposite side of the quote for market orders, the limit price for limit orders. With `ahead`
+/// shares still queued before a limit order, only a quote through its limit price fills it, since
+/// one only touching it has not cleared the queue.
+fn quote_fill_price(o: &Order, quote: &Quote, ahead: u32) -> Option<f64> {
+    match (&o.order_type, &o.side) {
+        (OrderType::Market, Side::Buy) => Some(quote.ask_price),
+        (OrderType::Market, Side::Sell) => Some(quote.bid_price),
+        (OrderType::Limit { limit_price }, Side::Buy)
+            if quote.ask_price < *limit_price
+                || (ahead == 0 && quote.ask_price <= *limit_price) =>
+        {
+            Some(*limit_price)
+        }
+        (OrderType::Limit { limit_price }, Side::Sell)
+            if quote.bid_price > *limit_price
+                || (ahead == 0 && quote.bid_price >= *limit_price) =>
+        {
+            Some(*limit_price)
+        }
+        _ => None,
+    }
+}
+
 fn is_marketable(o: &Order, price: f64) -> bool {
     match (&o.order_type, &o.side) {
         (OrderType::Market, _) => true,
         (OrderType::Limit { limit_price }, Side::Buy) => *limit_price >= price,
         (OrderType::Limit { limit_price }, Side::Sell) => *limit_price <= price,
-        (OrderType::Stop { stop_price }, Side::Buy) => *stop_price <= price,
-        (OrderType::Stop { stop_price }, Side::Sell) => *stop_price >= price,
-        (
-            OrderType::StopLimit {
-                limit_price,
-                stop_price,
+        // Stop orders have to be triggered first, see `trigger_stop`
+        (OrderType::Stop { .. }, _)
+        | (OrderType::StopLimit { .. }, _)
+        | (OrderType::TrailingStop { .. }, _) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::market::Tape;
+    use crate::order::types::{OrderClass, OrderIntent, StopLossSpec, TakeProfitSpec};
+    use chrono::{Duration, NaiveDate, NaiveTime};
+
+    /// An exchange trading AAPL during the regular session of 2020-09-18.
+    fn exchange() -> Exchange {
+        let mut exchange = Exchange::new(vec![Asset::from_symbol("AAPL")]);
+        let (open, _) = calendar::regular_session(NaiveDate::from_ymd(2020, 9, 18));
+        exchange.set_time((open + Duration::hours(1)).timestamp_nanos());
+        exchange
+    }
+
+    fn quote(bid_price: f64, ask_price: f64) -> Quote {
+        Quote {
+            symbol: "AAPL".into(),
+            bid_price,
+            bid_size: 100,
+            bid_exchange_id: 11,
+            ask_price,
+            ask_size: 100,
+            ask_exchange_id: 12,
+            sequence: 0,
+            timestamp: 0,
+            tape: Tape::C,
+        }
+    }
+
+    fn order(side: Side, order_type: OrderType) -> Order {
+        let asset = Asset::from_symbol("AAPL");
+        let intent = OrderIntent::new("AAPL")
+            .qty(10)
+            .side(side)
+            .order_type(order_type);
+        Order::from_intent(&intent, &asset, Utc::now()).unwrap()
+    }
+
+    fn trade(price: f64, size: u32) -> Trade {
+        Trade {
+            symbol: "AAPL".into(),
+            trade_id: "1".into(),
+            exchange_id: 11,
+            price,
+            size,
+            conditions: vec![],
+            timestamp: 0,
+            tape: Tape::C,
+        }
+    }
+
+    fn bar(open: f64, high: f64, low: f64, close: f64) -> Bar {
+        Bar {
+            symbol: "AAPL".into(),
+            open,
+            high,
+            low,
+            close,
+            volume: 1000.0,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn intrabar_paths() {
+        let b = bar(100.0, 101.0, 95.0, 97.0);
+        assert_eq!(
+            IntrabarPath::OpenHighLowClose.prices(&b),
+            [100.0, 101.0, 95.0, 97.0]
+        );
+        assert_eq!(
+            IntrabarPath::OpenLowHighClose.prices(&b),
+            [100.0, 95.0, 101.0, 97.0]
+        );
+        assert_eq!(
+            IntrabarPath::NearestExtremeFirst.prices(&b),
+            [100.0, 101.0, 95.0, 97.0]
+        );
+    }
+
+    #[test]
+    fn bar_fills_at_trigger_price() {
+        let mut exchange = exchange();
+        exchange.intrabar_path = IntrabarPath::OpenHighLowClose;
+        exchange.store(order(Side::Sell, OrderType::Stop { stop_price: 98.0 }));
+        exchange.store(order(Side::Sell, OrderType::Limit { limit_price: 100.5 }));
+        let fills = exchange.update_bar(&bar(100.0, 101.0, 95.0, 97.0));
+        assert_eq!(fills.len(), 2);
+        assert_eq!(fills[0].price, 100.5);
+        assert_eq!(fills[1].price, 98.0);
+        assert_eq!(*exchange.get_price("AAPL").unwrap(), 97.0);
+    }
+
+    #[test]
+    fn bar_gaps_fill_at_open() {
+        let mut exchange = exchange();
+        exchange.store(order(Side::Sell, OrderType::Stop { stop_price: 98.0 }));
+        let fills = exchange.update_bar(&bar(96.0, 97.0, 94.0, 95.0));
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].price, 96.0);
+    }
+
+    #[test]
+    fn market_orders_cross_the_spread() {
+        let mut exchange = exchange();
+        exchange.update_quote(quote(99.0, 101.0));
+        let buy = exchange
+            .transmit_order(order(Side::Buy, OrderType::Market))
+            .unwrap()
+            .unwrap();
+        assert_eq!(buy.price, 101.0);
+        let sell = exchange
+            .transmit_order(order(Side::Sell, OrderType::Market))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sell.price, 99.0);
+    }
+
+    #[test]
+    fn limit_orders_trigger_on_opposite_side() {
+        let mut exchange = exchange();
+        exchange.update_quote(quote(99.0, 101.0));
+        let fill = exchange
+            .transmit_order(order(Side::Buy, OrderType::Limit { limit_price: 100.0 }))
+            .unwrap();
+        assert!(fill.is_none());
+        assert!(exchange.update_quote(quote(99.5, 100.5)).is_empty());
+        let fills = exchange.update_quote(quote(99.5, 100.0));
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].price, 100.0);
+        assert!(exchange.stored_orders.is_empty());
+    }
+
+    #[test]
+    fn quote_fills_take_the_displayed_size() {
+        let mut exchange = exchange();
+        exchange.update_quote(quote(99.0, 101.0));
+        let mut buy = order(Side::Buy, OrderType::Limit { limit_price: 100.0 });
+        buy.qty = 150;
+        exchange.store(buy);
+        let fills = exchange.update_quote(quote(99.0, 99.5));
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].qty, 100);
+        assert_eq!(exchange.stored_orders[0].filled_qty, 100);
+        exchange.participation = Some(0.1);
+        let fills = exchange.update_quote(quote(99.0, 99.5));
+        assert_eq!(fills[0].qty, 10);
+        assert_eq!(exchange.stored_orders[0].filled_qty, 110);
+    }
+
+    #[test]
+    fn participation_limits_fills() {
+        let mut exchange = exchange();
+        exchange.participation = Some(0.1);
+        exchange.store(order(Side::Buy, OrderType::Limit { limit_price: 100.0 }));
+        exchange.store(order(Side::Buy, OrderType::Limit { limit_price: 100.0 }));
+        let fills = exchange.update_trade(&trade(100.0, 80));
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].qty, 8);
+        let fills = exchange.update_trade(&trade(99.0, 50));
+        assert_eq!(fills.len(), 2);
+        assert_eq!(fills[0].qty, 2);
+        assert_eq!(fills[1].qty, 3);
+        assert_eq!(exchange.stored_orders.len(), 1);
+        assert_eq!(exchange.stored_orders[0].filled_qty, 3);
+        assert!(exchange.update_trade(&trade(101.0, 1000)).is_empty());
+    }
+
+    #[test]
+    fn unlimited_participation_fills_completely() {
+        let mut exchange = exchange();
+        exchange.store(order(Side::Sell, OrderType::Limit { limit_price: 100.0 }));
+        let fills = exchange.update_trade(&trade(100.0, 1));
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].qty, -10);
+        assert!(exchange.stored_orders.is_empty());
+    }
+
+    #[test]
+    fn limit_orders_wait_in_queue() {
+        let mut exchange = exchange();
+        exchange.queue_model = QueueModel::Queue { default_size: 0 };
+        exchange.update_quote(quote(100.0, 100.5));
+        exchange.store(order(Side::Buy, OrderType::Limit { limit_price: 100.0 }));
+        assert_eq!(exchange.queue_ahead.values().next(), Some(&100));
+        assert!(exchange.update_trade(&trade(100.0, 60)).is_empty());
+        exchange.update_quote(quote(100.0, 100.5));
+        let fills = exchange.update_trade(&trade(100.0, 45));
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].qty, 5);
+        let fills = exchange.update_trade(&trade(99.5, 100));
+        assert_eq!(fills[0].qty, 5);
+        assert!(exchange.stored_orders.is_empty());
+        assert!(exchange.queue_ahead.is_empty());
+    }
+
+    #[test]
+    fn orders_at_one_price_share_the_volume() {
+        let mut exchange = exchange();
+        exchange.queue_model = QueueModel::Queue { default_size: 0 };
+        exchange.update_quote(quote(99.5, 100.5));
+        exchange.store(order(Side::Buy, OrderType::Limit { limit_price: 100.0 }));
+        exchange.store(order(Side::Buy, OrderType::Limit { limit_price: 100.0 }));
+        let fills = exchange.update_trade(&trade(100.0, 15));
+        assert_eq!(fills.len(), 2);
+        assert_eq!(fills[0].qty, 10);
+        assert_eq!(fills[1].qty, 5);
+        assert_eq!(exchange.stored_orders[0].filled_qty, 5);
+    }
+
+    #[test]
+    fn touching_quotes_respect_the_queue() {
+        let mut exchange = exchange();
+        exchange.queue_model = QueueModel::Queue { default_size: 0 };
+        exchange.update_quote(quote(100.0, 100.5));
+        exchange.store(order(Side::Buy, OrderType::Limit { limit_price: 100.0 }));
+        assert!(exchange.update_quote(quote(99.5, 100.0)).is_empty());
+        let fills = exchange.update_quote(quote(99.0, 99.5));
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].price, 100.0);
+    }
+
+    #[test]
+    fn improving_orders_lead_the_queue() {
+        let mut exchange = exchange();
+        exchange.queue_model = QueueModel::Queue { default_size: 500 };
+        exchange.update_quote(quote(99.0, 101.0));
+        exchange.store(order(Side::Sell, OrderType::Limit { limit_price: 100.5 }));
+        let fills = exchange.update_trade(&trade(100.5, 10));
+        assert_eq!(fills[0].qty, -10);
+    }
+
+    #[test]
+    fn slippage_stops_at_limit_price() {
+        let mut exchange = exchange();
+        exchange.slippage = Box::new(crate::slippage::FixedBps(100.0));
+        let market = exchange
+            .transmit_order(order(Side::Buy, OrderType::Market))
+            .unwrap()
+            .unwrap();
+        assert!((market.price - 101.0).abs() < 1e-9);
+        exchange.store(order(Side::Sell, OrderType::Limit { limit_price: 100.5 }));
+        let fills = exchange.update_trade(&trade(101.0, 100));
+        assert!((fills[0].price - 100.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn delayed_orders_fill_on_arrival() {
+        let mut exchange = exchange();
+        exchange.update_trade(&trade(100.0, 100));
+        let o = order(Side::Buy, OrderType::Market);
+        exchange.delay(o.clone(), 500);
+        assert!(exchange.release_orders(400).0.is_empty());
+        exchange.update_trade(&trade(101.0, 100));
+        let (arrived, fills) = exchange.release_orders(600);
+        assert_eq!(arrived, vec![o.id]);
+        assert_eq!(fills[0].price, 101.0);
+        assert!(exchange.in_flight.is_empty());
+    }
+
+    #[test]
+    fn orders_in_flight_at_the_end_are_rejected() {
+        let mut exchange = exchange();
+        let o = order(Side::Buy, OrderType::Limit { limit_price: 100.0 });
+        exchange.delay(o.clone(), 500);
+        assert_eq!(exchange.reject_in_flight(), vec![o.id]);
+        assert!(exchange.in_flight.is_empty());
+        assert!(exchange.release_orders(600).0.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn immediate_orders_are_never_stored() {
+        let mut exchange = exchange();
+        exchange.update_quote(quote(99.0, 101.0));
+        let mut ioc = order(Side::Buy, OrderType::Limit { limit_price: 101.0 });
+        ioc.qty = 150;
+        ioc.time_in_force = TimeInForce::IOC;
+        let fill = exchange.transmit_order(ioc.clone()).unwrap().unwrap();
+        assert_eq!(fill.qty, 100);
+        let mut fok = ioc.clone();
+        fok.time_in_force = TimeInForce::FOK;
+        assert!(exchange.transmit_order(fok.clone()).unwrap().is_none());
+        fok.qty = 100;
+        assert_eq!(exchange.transmit_order(fok).unwrap().unwrap().qty, 100);
+        ioc.order_type = OrderType::Limit { limit_price: 100.0 };
+        assert!(exchange.transmit_order(ioc).unwrap().is_none());
+        assert!(exchange.stored_orders.is_empty());
+    }
+
+    #[test]
+    fn day_orders_expire_at_the_close() {
+        let mut exchange = exchange();
+        let mut day = order(Side::Buy, OrderType::Limit { limit_price: 90.0 });
+        let mut gtc = day.clone();
+        gtc.id = Uuid::new_v4();
+        gtc.time_in_force = TimeInForce::GTC;
+        let mut extended = day.clone();
+        extended.id = Uuid::new_v4();
+        extended.extended_hours = true;
+        day.time_in_force = TimeInForce::DAY;
+        let date = NaiveDate::from_ymd(2020, 9, 18);
+        let (open, close) = calendar::regular_session(date);
+        exchange.set_time(open.timestamp_nanos());
+        exchange.store(day.clone());
+        exchange.store(gtc);
+        exchange.store(extended.clone());
+        assert_eq!(exchange.expiries.len(), 2);
+        assert!(exchange
+            .expire_orders(close.timestamp_nanos() - 1)
+            .is_empty());
+        let expired = exchange.expire_orders(close.timestamp_nanos());
+        assert_eq!(expired, vec![(day.id, close.timestamp_nanos())]);
+        let expired = exchange.expire_orders((close + Duration::hours(4)).timestamp_nanos());
+        assert_eq!(expired[0].0, extended.id);
+        assert_eq!(exchange.stored_orders.len(), 1);
+        assert!(exchange.expiries.is_empty());
+        // After the close, a DAY order is good for the next session
+        assert_eq!(
+            day_order_expiry(&day, close),
+            calendar::regular_session(NaiveDate::from_ymd(2020, 9, 21)).1
+        );
+        // On early close days, DAY orders expire at 13:00
+        let early_close = NaiveDate::from_ymd(2020, 11, 27);
+        let (open, _) = calendar::regular_session(early_close);
+        assert_eq!(
+            day_order_expiry(&day, open),
+            Utc.ymd(2020, 11, 27).and_hms(18, 0, 0)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn bracket_legs_cancel_each_other() {
+        let mut exchange = exchange();
+        let intent = OrderIntent::new("AAPL")
+            .qty(10)
+            .order_class(OrderClass::Bracket {
+                take_profit: TakeProfitSpec { limit_price: 110.0 },
+                stop_loss: StopLossSpec {
+                    stop_price: 90.0,
+                    limit_price: None,
+                },
+            });
+        let bracket = Order::from_intent(&intent, &Asset::from_symbol("AAPL"), Utc::now()).unwrap();
+        let fill = exchange.transmit_order(bracket.clone()).unwrap();
+        assert!(exchange.stored_orders.is_empty());
+        exchange.link_orders(fill.iter());
+        assert_eq!(exchange.stored_orders.len(), 2);
+        assert!(exchange
+            .stored_orders
+            .iter()
+            .all(|o| o.status == OrderStatus::New));
+        let fills = exchange.update_trade(&trade(111.0, 100));
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].order.id, bracket.held_legs()[0].id);
+        exchange.link_orders(fills.iter());
+        assert!(exchange.stored_orders.is_empty());
+        assert!(exchange.siblings.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn oco_legs_work_together() {
+        let mut exchange = exchange();
+        let intent = OrderIntent::new("AAPL")
+            .qty(10)
+            .side(Side::Sell)
+            .order_type(OrderType::Limit { limit_price: 110.0 })
+            .order_class(OrderClass::OCO {
+                take_profit: TakeProfitSpec { limit_price: 110.0 },
+                stop_loss: StopLossSpec {
+                    stop_price: 90.0,
+                    limit_price: None,
+                },
+            });
+        let oco = Order::from_intent(&intent, &Asset::from_symbol("AAPL"), Utc::now()).unwrap();
+        exchange.update_trade(&trade(100.0, 100));
+        assert!(exchange.transmit_order(oco.clone()).unwrap().is_none());
+        assert_eq!(exchange.stored_orders.len(), 2);
+        let fills = exchange.update_trade(&trade(89.0, 100));
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].order.id, oco.active_legs()[0].id);
+        exchange.link_orders(fills.iter());
+        assert!(exchange.stored_orders.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn rejected_ocos_leave_no_stop_loss() {
+        let mut exchange = exchange();
+        let intent = OrderIntent::new("AAPL")
+            .qty(10)
+            .side(Side::Sell)
+            .order_type(OrderType::Limit { limit_price: 110.0 })
+            .order_class(OrderClass::OCO {
+                take_profit: TakeProfitSpec { limit_price: 110.0 },
+                stop_loss: StopLossSpec {
+                    stop_price: 90.0,
+                    limit_price: None,
+                },
+            });
+        let oco = Order::from_intent(&intent, &Asset::from_symbol("AAPL"), Utc::now()).unwrap();
+        // No trade has set a price for the primary order
+        assert!(exchange.transmit_order(oco).is_err());
+        assert!(exchange.stored_orders.is_empty());
+        assert!(exchange.siblings.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn canceling_an_oco_cancels_its_stop_loss() {
+        let mut exchange = exchange();
+        let intent = OrderIntent::new("AAPL")
+            .qty(10)
+            .side(Side::Sell)
+            .order_type(OrderType::Limit { limit_price: 110.0 })
+            .order_class(OrderClass::OCO {
+                take_profit: TakeProfitSpec { limit_price: 110.0 },
+                stop_loss: StopLossSpec {
+                    stop_price: 90.0,
+                    limit_price: None,
+                },
+            });
+        let oco = Order::from_intent(&intent, &Asset::from_symbol("AAPL"), Utc::now()).unwrap();
+        exchange.update_trade(&trade(100.0, 100));
+        assert!(exchange.transmit_order(oco.clone()).unwrap().is_none());
+        assert_eq!(exchange.stored_orders.len(), 2);
+        exchange.cancel(&oco.id);
+        assert!(exchange.stored_orders.is_empty());
+        assert!(exchange.siblings.is_empty());
+        assert!(exchange.update_trade(&trade(89.0, 100)).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn oto_legs_follow_the_filled_quantity() {
+        let mut exchange = exchange();
+        exchange.participation = Some(0.1);
+        let intent = OrderIntent::new("AAPL")
+            .qty(10)
+            .order_type(OrderType::Limit { limit_price: 100.0 })
+            .order_class(OrderClass::OTO {
+                take_profit: Some(TakeProfitSpec { limit_price: 110.0 }),
+                stop_loss: None,
+            });
+        let parent = Order::from_intent(&intent, &Asset::from_symbol("AAPL"), Utc::now()).unwrap();
+        let leg = parent.held_legs()[0].id;
+        exchange.store(parent.clone());
+        let fills = exchange.update_trade(&trade(100.0, 40));
+        exchange.link_orders(fills.iter());
+        let released = exchange.stored_orders.iter().find(|o| o.id == leg).unwrap();
+        assert_eq!(released.qty, 4);
+        let fills = exchange.update_trade(&trade(100.0, 40));
+        exchange.link_orders(fills.iter());
+        let released = exchange.stored_orders.iter().find(|o| o.id == leg).unwrap();
+        assert_eq!(released.qty, 8);
+        exchange.cancel(&parent.id);
+        assert_eq!(exchange.stored_orders.len(), 1);
+        assert!(exchange.children.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn queued_legs_follow_the_filled_quantity() {
+        let mut exchange = exchange();
+        exchange.participation = Some(0.1);
+        let (open, _) = calendar::regular_session(NaiveDate::from_ymd(2020, 9, 18));
+        exchange.set_time((open - Duration::hours(1)).timestamp_nanos());
+        let intent = OrderIntent::new("AAPL")
+            .qty(10)
+            .order_type(OrderType::Limit { limit_price: 100.0 })
+            .extended_hours(true)
+            .order_class(OrderClass::OTO {
+                take_profit: Some(TakeProfitSpec { limit_price: 110.0 }),
+                stop_loss: None,
+            });
+        let parent = Order::from_intent(&intent, &Asset::from_symbol("AAPL"), Utc::now()).unwrap();
+        let leg = parent.held_legs()[0].id;
+        exchange.store(parent);
+        // The take profit leg may only trade in the regular session
+        let fills = exchange.update_trade(&trade(100.0, 40));
+        exchange.link_orders(fills.iter());
+        let released = exchange.queued_orders.iter().find(|o| o.id == leg).unwrap();
+        assert_eq!(released.qty, 4);
+        let fills = exchange.update_trade(&trade(100.0, 40));
+        exchange.link_orders(fills.iter());
+        let released = exchange.queued_orders.iter().find(|o| o.id == leg).unwrap();
+        assert_eq!(released.qty, 8);
+        exchange.set_time(open.timestamp_nanos());
+        assert!(exchange.stored_orders.iter().any(|o| o.id == leg));
+    }
+
+    #[actix_rt::test]
+    async fn trailing_stops_follow_trades() {
+        let mut exchange = exchange();
+        let trailing = order(
+            Side::Sell,
+            OrderType::TrailingStop {
+                trail_price: Some(2.0),
+                trail_percent: None,
+                hwm: None,
+                stop_price: None,
             },
+        );
+        assert!(exchange.transmit_order(trailing).unwrap().is_none());
+        assert!(exchange.update_trade(&trade(103.0, 100)).is_empty());
+        assert!(exchange.update_trade(&trade(101.5, 100)).is_empty());
+        assert_eq!(
+            exchange.stored_orders[0].order_type,
+            OrderType::TrailingStop {
+                trail_price: Some(2.0),
+                trail_percent: None,
+                hwm: Some(103.0),
+                stop_price: Some(101.0),
+            }
+        );
+        let fills = exchange.update_trade(&trade(100.5, 100));
+        assert_eq!(fills[0].price, 100.5);
+    }
+
+    #[test]
+    fn stop_limits_stay_live_once_triggered() {
+        let mut exchange = exchange();
+        let stop_limit = OrderType::StopLimit {
+            stop_price: 101.0,
+            limit_price: 102.0,
+        };
+        exchange.store(order(Side::Buy, stop_limit));
+        // Gapping through both the stop and the limit triggers the order without filling it
+        assert!(exchange.update_trade(&trade(103.0, 100)).is_empty());
+        assert_eq!(
+            exchange.stored_orders[0].order_type,
+            OrderType::Limit { limit_price: 102.0 }
+        );
+        // Moving back below the stop no longer matters
+        let fills = exchange.update_trade(&trade(100.0, 100));
+        assert_eq!(fills[0].price, 100.0);
+    }
+
+    #[test]
+    fn stops_become_market_orders() {
+        let mut exchange = exchange();
+        exchange.intrabar_path = IntrabarPath::OpenHighLowClose;
+        exchange.store(order(
             Side::Buy,
-        ) => *limit_price >= price && price >= *stop_price,
-        (
             OrderType::StopLimit {
-                limit_price,
-                stop_price,
+                stop_price: 101.0,
+                limit_price: 101.5,
             },
-            Side::Sell,
-        ) => *limit_price <= price && price <= *stop_price,
+        ));
+        // The bar crosses the stop on its way to a high above the limit
+        let fills = exchange.update_bar(&bar(100.0, 103.0, 99.0, 100.0));
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].price, 101.0);
+        let stop = order(Side::Sell, OrderType::Stop { stop_price: 105.0 });
+        let fill = exchange.transmit_order(stop).unwrap().unwrap();
+        assert_eq!(fill.price, 100.0);
+    }
+
+    #[test]
+    fn market_status_follows_the_sessions() {
+        let at = |h, m| {
+            MarketStatus::at(calendar::new_york_time(
+                NaiveDate::from_ymd(2020, 9, 18),
+                NaiveTime::from_hms(h, m, 0),
+            ))
+        };
+        assert_eq!(at(3, 59), MarketStatus::Closed);
+        assert_eq!(at(4, 0), MarketStatus::PreOpen);
+        assert_eq!(at(9, 30), MarketStatus::Open);
+        assert_eq!(at(16, 0), MarketStatus::PostClose);
+        assert_eq!(at(20, 0), MarketStatus::Closed);
+        // Saturday
+        let weekend = calendar::new_york_time(
+            NaiveDate::from_ymd(2020, 9, 19),
+            NaiveTime::from_hms(12, 0, 0),
+        );
+        assert_eq!(MarketStatus::at(weekend), MarketStatus::Closed);
+        let early_close = |h, m| {
+            MarketStatus::at(calendar::new_york_time(
+                NaiveDate::from_ymd(2020, 11, 27),
+                NaiveTime::from_hms(h, m, 0),
+            ))
+        };
+        assert_eq!(early_close(12, 59), MarketStatus::Open);
+        assert_eq!(early_close(13, 0), MarketStatus::PostClose);
+        assert_eq!(early_close(17, 0), MarketStatus::Closed);
+    }
+
+    #[test]
+    fn orders_wait_for_their_session() {
+        let mut exchange = exchange();
+        let date = NaiveDate::from_ymd(2020, 9, 18);
+        let (open, close) = calendar::regular_session(date);
+        exchange.set_time((open - Duration::hours(1)).timestamp_nanos());
+        assert_eq!(exchange.market_status(), MarketStatus::PreOpen);
+        let regular = order(Side::Buy, OrderType::Market);
+        let mut extended = order(Side::Buy, OrderType::Limit { limit_price: 101.0 });
+        extended.extended_hours = true;
+        assert!(exchange.transmit_order(regular.clone()).unwrap().is_none());
+        assert!(exchange.transmit_order(extended.clone()).unwrap().is_some());
+        assert_eq!(exchange.queued_orders[0].id, regular.id);
+        // The market order works from the open, at the first price of the session
+        exchange.set_time(open.timestamp_nanos());
+        assert!(exchange.is_open());
+        assert!(exchange.queued_orders.is_empty());
+        let fills = exchange.update_trade(&trade(102.0, 100));
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].order.id, regular.id);
+        assert_eq!(fills[0].price, 102.0);
+        // Resting orders that may not trade after the close wait for the next open
+        let mut gtc = order(Side::Buy, OrderType::Limit { limit_price: 90.0 });
+        gtc.time_in_force = TimeInForce::GTC;
+        exchange.transmit_order(gtc.clone()).unwrap();
+        exchange.set_time(close.timestamp_nanos());
+        assert_eq!(exchange.market_status(), MarketStatus::PostClose);
+        assert!(exchange.update_trade(&trade(89.0, 100)).is_empty());
+        assert_eq!(exchange.queued_orders[0].id, gtc.id);
     }
 }
@@ -0,0 +1,109 @@
+use crate::order::types::Side;
+use serde::Deserialize;
+
+/// Commissions and regulatory fees charged on executions. Every rate defaults to zero, as at
+/// Alpaca, so fees have to be opted into.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeSchedule {
+    /// Commission per share traded.
+    pub per_share: f64,
+    /// Commission per order, charged on its first fill.
+    pub per_order: f64,
+    /// Commission in basis points of the traded value.
+    pub bps: f64,
+    /// SEC Section 31 fee on sales, in dollars per million of proceeds.
+    pub sec_fee_per_million: f64,
+    /// FINRA Trading Activity Fee per share sold.
+    pub finra_taf_per_share: f64,
+    /// Most FINRA TAF charged on a single fill, uncapped if unset.
+    pub finra_taf_max: Option<f64>,
+}
+
+/// The fees charged on a single fill, in dollars.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Fees {
+    pub commission: f64,
+    pub sec_fee: f64,
+    pub finra_taf: f64,
+}
+
+impl Fees {
+    pub fn total(&self) -> f64 {
+        self.commission + self.sec_fee + self.finra_taf
+    }
+}
+
+impl FeeSchedule {
+    /// The fees on filling `qty` shares at `price`. `first_fill` tells whether this is the first
+    /// execution of its order, which carries the per order commission.
+    pub fn fees(&self, side: &Side, qty: u32, price: f64, first_fill: bool) -> Fees {
+        let notional = qty as f64 * price;
+        let mut commission = self.per_share * qty as f64 + self.bps * notional / 10_000.0;
+        if first_fill {
+            commission += self.per_order;
+        }
+        let (sec_fee, finra_taf) = match side {
+            Side::Buy => (0.0, 0.0),
+            Side::Sell => {
+                let taf = self.finra_taf_per_share * qty as f64;
+                (
+                    round_up_to_cent(notional * self.sec_fee_per_million / 1_000_000.0),
+                    round_up_to_cent(self.finra_taf_max.map_or(taf, |max| taf.min(max))),
+                )
+            }
+        };
+        Fees {
+            commission,
+            sec_fee,
+            finra_taf,
+        }
+    }
+}
+
+/// Regulatory fees are rounded up to the nearest cent.
+fn round_up_to_cent(amount: f64) -> f64 {
+    // Strip representation error first so that exact cents are not rounded up
+    ((amount * 100.0 * 1e6).round() / 1e6).ceil() / 100.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commission_free_by_default() {
+        let fees = FeeSchedule::default().fees(&Side::Sell, 100, 100.0, true);
+        assert_eq!(fees.total(), 0.0);
+    }
+
+    #[test]
+    fn commissions() {
+        let schedule = FeeSchedule {
+            per_share: 0.005,
+            per_order: 1.0,
+            bps: 1.0,
+            ..FeeSchedule::default()
+        };
+        let first = schedule.fees(&Side::Buy, 100, 100.0, true);
+        assert!((first.commission - 2.5).abs() < 1e-9);
+        let later = schedule.fees(&Side::Buy, 100, 100.0, false);
+        assert!((later.commission - 1.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn regulatory_fees_on_sells() {
+        let schedule = FeeSchedule {
+            sec_fee_per_million: 22.1,
+            finra_taf_per_share: 0.000119,
+            finra_taf_max: Some(5.95),
+            ..FeeSchedule::default()
+        };
+        assert_eq!(schedule.fees(&Side::Buy, 1000, 100.0, true).total(), 0.0);
+        let fees = schedule.fees(&Side::Sell, 1000, 100.0, true);
+        assert!((fees.sec_fee - 2.21).abs() < 1e-9);
+        assert!((fees.finra_taf - 0.12).abs() < 1e-9);
+        let capped = schedule.fees(&Side::Sell, 100_000, 10.0, true);
+        assert!((capped.finra_taf - 5.95).abs() < 1e-9);
+    }
+}
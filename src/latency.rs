@@ -0,0 +1,64 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+
+/// Simulated delay between the API accepting an order and the exchange receiving it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Latency {
+    /// Fixed part of the delay, in milliseconds.
+    pub delay_ms: f64,
+    /// Upper bound of a uniformly distributed extra delay, in milliseconds.
+    pub jitter_ms: f64,
+    /// Seed of the jitter, so that runs are reproducible.
+    pub seed: u64,
+}
+
+pub struct LatencySampler {
+    latency: Latency,
+    rng: StdRng,
+}
+
+impl LatencySampler {
+    pub fn new(latency: Latency) -> Self {
+        LatencySampler {
+            latency,
+            rng: StdRng::seed_from_u64(latency.seed),
+        }
+    }
+
+    /// The delay of the next order, in nanoseconds.
+    pub fn sample(&mut self) -> i64 {
+        let jitter = if self.latency.jitter_ms > 0.0 {
+            self.rng.gen_range(0.0, self.latency.jitter_ms)
+        } else {
+            0.0
+        };
+        ((self.latency.delay_ms + jitter) * 1e6) as i64
+    }
+}
+
+impl Default for LatencySampler {
+    fn default() -> Self {
+        Self::new(Latency::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sample() {
+        assert_eq!(LatencySampler::default().sample(), 0);
+        let latency = Latency {
+            delay_ms: 5.0,
+            jitter_ms: 2.0,
+            seed: 1,
+        };
+        let mut sampler = LatencySampler::new(latency);
+        let delays: Vec<i64> = (0..100).map(|_| sampler.sample()).collect();
+        assert!(delays.iter().all(|d| *d >= 5_000_000 && *d < 7_000_000));
+        let mut again = LatencySampler::new(latency);
+        assert!(delays.iter().all(|d| *d == again.sample()));
+    }
+}
@@ -2,10 +2,15 @@
 pub mod account;
 pub mod account_configurations;
 pub mod asset;
+pub mod calendar;
 pub mod clock;
+pub mod config;
 pub mod errors;
 pub mod exchange;
+pub mod fees;
+pub mod latency;
 pub mod market;
 pub mod order;
 pub mod position;
+pub mod slippage;
 mod utils;
@@ -1,6 +1,8 @@
 use acticks::{
-    account, asset, clock, exchange,
-    market::{self},
+    account, asset, calendar, clock,
+    config::{Args, Config},
+    exchange,
+    market::{self, MarketSource},
     order, position,
 };
 use actix::registry::SystemService;
@@ -9,7 +11,9 @@ use actix_web::{
     web::{self, Json, Path, Query},
     App, HttpResponse, HttpServer, Result,
 };
+use chrono::{DateTime, NaiveTime, Utc};
 use serde::Deserialize;
+use structopt::StructOpt;
 use tracing::subscriber::set_global_default;
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
@@ -17,13 +21,19 @@ use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
 use uuid::Uuid;
 
 async fn get_clock() -> Result<HttpResponse> {
-    HttpResponse::Ok().json(clock::get_clock()).await
+    HttpResponse::Ok().json(clock::get_clock().await?).await
 }
 
 async fn get_account() -> Result<HttpResponse> {
     HttpResponse::Ok().json(account::get_account().await?).await
 }
 
+async fn get_activities() -> Result<HttpResponse> {
+    HttpResponse::Ok()
+        .json(account::get_activities().await?)
+        .await
+}
+
 async fn get_assets() -> Result<HttpResponse> {
     let assets: Vec<asset::types::Asset> = asset::get_assets().await?.values().cloned().collect();
     HttpResponse::Ok().json(assets).await
@@ -99,12 +109,139 @@ async fn close_positions() -> Result<HttpResponse> {
     HttpResponse::Ok().await
 }
 
+type MarketControl = actix::Recipient<market::Control>;
+
+async fn send_replay_command(
+    market: web::Data<MarketControl>,
+    command: market::ReplayCommand,
+) -> Result<HttpResponse> {
+    market
+        .send(market::Control(command))
+        .await
+        .map_err(acticks::errors::Error::from)??;
+    HttpResponse::Ok().await
+}
+
+async fn pause_replay(market: web::Data<MarketControl>) -> Result<HttpResponse> {
+    send_replay_command(market, market::ReplayCommand::Pause).await
+}
+
+async fn resume_replay(market: web::Data<MarketControl>) -> Result<HttpResponse> {
+    send_replay_command(market, market::ReplayCommand::Resume).await
+}
+
+#[derive(Deserialize)]
+struct SpeedBody {
+    speed: market::Speed,
+}
+
+async fn set_replay_speed(
+    market: web::Data<MarketControl>,
+    body: Json<SpeedBody>,
+) -> Result<HttpResponse> {
+    let command = market::ReplayCommand::SetSpeed { speed: body.speed };
+    send_replay_command(market, command).await
+}
+
+#[derive(Deserialize)]
+struct StepBody {
+    events: usize,
+}
+
+async fn step_replay(
+    market: web::Data<MarketControl>,
+    body: Json<StepBody>,
+) -> Result<HttpResponse> {
+    let command = market::ReplayCommand::Step {
+        events: body.events,
+    };
+    send_replay_command(market, command).await
+}
+
+#[derive(Deserialize)]
+struct SeekBody {
+    timestamp: DateTime<Utc>,
+}
+
+async fn seek_replay(
+    market: web::Data<MarketControl>,
+    body: Json<SeekBody>,
+) -> Result<HttpResponse> {
+    let command = market::ReplayCommand::SeekTo {
+        timestamp: body.timestamp,
+    };
+    send_replay_command(market, command).await
+}
+
+/// Points the market picked in `config` at its data.
+async fn configure_market(config: &Config) -> Result<()> {
+    let directory = config.data_directory.clone();
+    match config.source {
+        MarketSource::Files => {
+            if let Some(directory) = directory {
+                market::file::FileMarket::from_registry()
+                    .send(market::file::SetDirectory(directory))
+                    .await
+                    .map_err(acticks::errors::Error::from)?;
+            }
+        }
+        MarketSource::Bars => {
+            if let Some(directory) = directory {
+                market::bars::BarMarket::from_registry()
+                    .send(market::bars::SetDirectory(directory))
+                    .await
+                    .map_err(acticks::errors::Error::from)?;
+            }
+        }
+        MarketSource::Synthetic => {
+            market::synthetic::SyntheticMarket::from_registry()
+                .send(market::synthetic::Configure(config.synthetic.clone()))
+                .await
+                .map_err(acticks::errors::Error::from)?;
+        }
+        #[cfg(feature = "polygon")]
+        MarketSource::Polygon => {
+            if let Some(directory) = directory {
+                market::polygon::historical::PolygonMarket::from_registry()
+                    .send(market::polygon::historical::SetCacheDirectory(Some(
+                        directory,
+                    )))
+                    .await
+                    .map_err(acticks::errors::Error::from)?;
+            }
+        }
+    }
+    Ok(())
+}
+
 async fn initialize_actors(
+    recipients: market::MarketRecipients,
     cash: f64,
     symbols: Vec<String>,
-) -> Result<actix::prelude::Request<market::polygon::historical::PolygonMarket, market::Start>> {
+    sessions: market::Sessions,
+    quotes: bool,
+    filter: market::TradeFilter,
+    speed: market::Speed,
+) -> Result<(
+    MarketControl,
+    actix::prelude::RecipientRequest<market::Start>,
+)> {
+    if let Some(first_session) = sessions.dates().first() {
+        let start = calendar::new_york_time(*first_session, NaiveTime::from_hms(0, 0, 0));
+        clock::SimulationClock::from_registry()
+            .send(clock::SetTime(start))
+            .await
+            .unwrap();
+        exchange::Exchange::from_registry()
+            .send(clock::SetTime(start))
+            .await
+            .unwrap();
+    }
     account::actors::AccountManager::from_registry()
-        .send(account::actors::SetCash(cash))
+        .send(account::actors::SetCash {
+            cash,
+            time: clock::now().await?,
+        })
         .await
         .unwrap();
     asset::actors::AssetManager::from_registry()
@@ -121,25 +258,66 @@ async fn initialize_actors(
         .send(exchange::SetAssets { assets })
         .await
         .unwrap();
-    let market_addr = market::polygon::historical::PolygonMarket::from_registry();
-    market_addr
-        .send(market::Initialize(symbols))
+    recipients
+        .initialize
+        .send(market::Initialize {
+            symbols,
+            sessions,
+            quotes,
+            filter,
+        })
         .await
         .unwrap()?;
-    market_addr.do_send(market::Subscribe(
-        exchange::Exchange::from_registry().recipient(),
-    ));
-    market_addr.do_send(market::Subscribe(
-        position::actors::PositionManager::from_registry().recipient(),
-    ));
-    Ok(market_addr.send(market::Start(60)))
+    // The clock subscribes first so that everyone else sees the time of the current event
+    let clock_addr = clock::SimulationClock::from_registry();
+    let exchange_addr = exchange::Exchange::from_registry();
+    let position_addr = position::actors::PositionManager::from_registry();
+    for trades in vec![
+        clock_addr.clone().recipient(),
+        exchange_addr.clone().recipient(),
+        position_addr.clone().recipient(),
+    ] {
+        recipients
+            .subscribe
+            .send(market::Subscribe(trades))
+            .await
+            .unwrap();
+    }
+    for quotes in vec![
+        clock_addr.clone().recipient(),
+        exchange_addr.clone().recipient(),
+    ] {
+        recipients
+            .subscribe_quotes
+            .send(market::SubscribeQuotes(quotes))
+            .await
+            .unwrap();
+    }
+    for bars in vec![
+        clock_addr.recipient(),
+        exchange_addr.recipient(),
+        position_addr.recipient(),
+    ] {
+        recipients
+            .subscribe_bars
+            .send(market::SubscribeBars(bars))
+            .await
+            .unwrap();
+    }
+    let control = recipients.control.clone();
+    Ok((control, recipients.start.send(market::Start(speed))))
 }
 
 #[actix_web::main]
 async fn main() -> Result<()> {
+    let config = Config::load(Args::from_args())?;
+
     LogTracer::init().expect("Failed to set logger");
 
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("info"));
+    let env_filter = match &config.log_level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("info")),
+    };
     let formatting_layer = BunyanFormattingLayer::new("acticks".into(), std::io::stdout);
     let subscriber = Registry::default()
         .with(env_filter)
@@ -147,13 +325,43 @@ async fn main() -> Result<()> {
         .with(formatting_layer);
     set_global_default(subscriber).expect("Failed to set subscriber");
 
-    let cash: f64 = 1_000_000.0;
-    let symbols = vec!["AAPL".into()]; //, "TSLA".into()];
-    let market_fut = initialize_actors(cash, symbols).await?;
+    configure_market(&config).await?;
+    exchange::Exchange::from_registry()
+        .send(exchange::SetParticipation(config.participation))
+        .await
+        .map_err(acticks::errors::Error::from)?;
+    exchange::Exchange::from_registry()
+        .send(exchange::SetQueueModel(config.queue_model))
+        .await
+        .map_err(acticks::errors::Error::from)?;
+    exchange::Exchange::from_registry()
+        .send(exchange::SetSlippage(config.slippage.model()))
+        .await
+        .map_err(acticks::errors::Error::from)?;
+    exchange::Exchange::from_registry()
+        .send(exchange::SetLatency(config.latency))
+        .await
+        .map_err(acticks::errors::Error::from)?;
+    exchange::Exchange::from_registry()
+        .send(exchange::SetFees(config.fees))
+        .await
+        .map_err(acticks::errors::Error::from)?;
+    let (market_control, market_fut) = initialize_actors(
+        config.source.market(),
+        config.cash,
+        config.symbols.clone(),
+        config.sessions(),
+        config.quotes,
+        config.filter.clone(),
+        config.speed,
+    )
+    .await?;
     let server_fut = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
+            .data(market_control.clone())
             .route("/account", web::get().to(get_account))
+            .route("/account/activities", web::get().to(get_activities))
             .route("/clock", web::get().to(get_clock))
             .route("/assets", web::get().to(get_assets))
             .route("/assets/{symbol_or_id}", web::get().to(get_asset))
@@ -170,9 +378,22 @@ async fn main() -> Result<()> {
             .route("/positions/{symbol}", web::get().to(get_position_by_symbol))
             .route("/positions/{symbol}", web::delete().to(close_position))
             .route("/positions", web::delete().to(close_positions))
+            .route("/admin/replay/pause", web::post().to(pause_replay))
+            .route("/admin/replay/resume", web::post().to(resume_replay))
+            .route("/admin/replay/speed", web::post().to(set_replay_speed))
+            .route("/admin/replay/step", web::post().to(step_replay))
+            .route("/admin/replay/seek", web::post().to(seek_replay))
     })
-    .bind("127.0.0.1:8000")?
+    .bind(&config.bind_address)?
     .run();
+    let market_fut = Box::pin(async move {
+        if market_fut.await.is_ok() {
+            // Nothing is left to trade against the orders still on their way to the exchange
+            let _ = exchange::Exchange::from_registry()
+                .send(exchange::EndReplay)
+                .await;
+        }
+    });
     futures::future::select(market_fut, server_fut).await;
     Ok(())
 }
@@ -0,0 +1,201 @@
+use super::*;
+use crate::errors::Result;
+use serde_json;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, trace, warn};
+
+/// Replays OHLCV bars from `<directory>/<SYMBOL>.csv` or `<directory>/<SYMBOL>.jsonl`, one file
+/// per symbol holding bars of any frequency. Only the bars closing during one of the requested
+/// sessions are replayed.
+pub struct BarMarket {
+    directory: PathBuf,
+    subscribers: Subscribers,
+    sources: Vec<BoxedSource>,
+    replay: ReplayHandle,
+}
+
+impl Default for BarMarket {
+    fn default() -> Self {
+        let directory = std::env::var("BAR_DATA_DIR").unwrap_or_else(|_| "bars".into());
+        Self::new(directory)
+    }
+}
+
+impl actix::Supervised for BarMarket {}
+
+impl SystemService for BarMarket {}
+
+impl BarMarket {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        BarMarket {
+            directory: directory.into(),
+            subscribers: Subscribers::default(),
+            sources: vec![],
+            replay: ReplayHandle::default(),
+        }
+    }
+
+    fn symbol_file(&self, symbol: &str) -> Option<PathBuf> {
+        ["csv", "jsonl"]
+            .iter()
+            .map(|ext| self.directory.join(format!("{}.{}", symbol, ext)))
+            .find(|path| path.exists())
+    }
+}
+
+/// Lazily reads the bars of `path`, in the order they appear in the file.
+pub fn read_bars(
+    path: &Path,
+    symbol: &str,
+) -> Result<Box<dyn Iterator<Item = Result<Bar>> + Send>> {
+    let reader = BufReader::new(File::open(path)?);
+    let symbol = symbol.to_string();
+    let bars: Box<dyn Iterator<Item = Result<Bar>> + Send> =
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => Box::new(
+                csv::Reader::from_reader(reader)
+                    .into_deserialize::<Bar>()
+                    .map(|bar| -> Result<Bar> { Ok(bar?) }),
+            ),
+            Some("jsonl") => Box::new(
+                reader
+                    .lines()
+                    .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+                    .map(|line| -> Result<Bar> { Ok(serde_json::from_str(&line?)?) }),
+            ),
+            _ => Box::new(std::iter::empty()),
+        };
+    Ok(Box::new(bars.map(move |bar| -> Result<Bar> {
+        let mut bar = bar?;
+        bar.symbol = symbol.clone();
+        Ok(bar)
+    })))
+}
+
+pub fn load_bars(path: &Path, symbol: &str) -> Result<Vec<Bar>> {
+    read_bars(path, symbol)?.collect()
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetDirectory(pub PathBuf);
+
+impl Actor for BarMarket {
+    type Context = Context<Self>;
+}
+
+impl Handler<SetDirectory> for BarMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetDirectory, _ctx: &mut Context<Self>) {
+        self.directory = msg.0;
+    }
+}
+
+impl Handler<Subscribe> for BarMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _ctx: &mut Context<Self>) {
+        self.subscribers.trades.push(msg.0);
+    }
+}
+
+impl Handler<SubscribeQuotes> for BarMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeQuotes, _ctx: &mut Context<Self>) {
+        self.subscribers.quotes.push(msg.0);
+    }
+}
+
+impl Handler<SubscribeBars> for BarMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeBars, _ctx: &mut Context<Self>) {
+        self.subscribers.bars.push(msg.0);
+    }
+}
+
+impl Handler<Start> for BarMarket {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: Start, _ctx: &mut Context<Self>) -> Self::Result {
+        debug!("Scheduling events");
+        let (handle, fut) = replay::replay(
+            EventMerge::new(std::mem::take(&mut self.sources)),
+            self.subscribers.clone(),
+            msg.0,
+        );
+        self.replay = handle;
+        fut
+    }
+}
+
+impl Handler<Control> for BarMarket {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: Control, _ctx: &mut Context<Self>) -> Self::Result {
+        self.replay.send(msg.0)
+    }
+}
+
+impl Handler<Initialize> for BarMarket {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: Initialize, _ctx: &mut Context<Self>) -> Self::Result {
+        info!("Reading bars from {:?}", &self.directory);
+        if msg.quotes {
+            warn!("Bar data has no quotes, only bars will be replayed");
+        }
+        let dates: HashSet<NaiveDate> = msg.sessions.dates().into_iter().collect();
+        let mut sources = vec![];
+        for symbol in msg.symbols {
+            match self.symbol_file(&symbol) {
+                Some(path) => {
+                    trace!("Reading file: {:?}", &path);
+                    let dates = dates.clone();
+                    let bars = read_bars(&path, &symbol)?
+                        .filter(move |bar| {
+                            bar.as_ref().map_or(true, |bar| {
+                                dates.contains(&calendar::session_date(bar.timestamp))
+                            })
+                        })
+                        .map(|bar| bar.map(MarketEvent::Bar));
+                    sources.push(Box::new(IterSource::new(bars)) as BoxedSource);
+                }
+                None => warn!("No bars for {}", symbol),
+            }
+        }
+        self.sources = sources;
+        Ok(())
+    }
+}
+
+impl Market for BarMarket {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use uuid::Uuid;
+
+    #[test]
+    fn load_csv() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("AAPL.csv");
+        fs::write(
+            &path,
+            "t,o,h,l,c,v\n\
+             1600459200000000000,106.0,110.0,105.0,107.0,1000\n",
+        )
+        .unwrap();
+        let bars = load_bars(&path, "AAPL").unwrap();
+        assert_eq!(bars.len(), 1);
+        assert_eq!(bars[0].symbol, "AAPL");
+        assert_eq!(bars[0].high, 110.0);
+    }
+}
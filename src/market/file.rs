@@ -0,0 +1,460 @@
+use super::*;
+use crate::errors::{Error, Result};
+use chrono::NaiveDate;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, trace, warn};
+
+/// Record layout shared by the CSV and JSONL tick files. The symbol is implied by the directory
+/// the file lives in, so it is not repeated on every row.
+#[derive(Deserialize)]
+struct FileTrade {
+    #[serde(rename = "i")]
+    trade_id: String,
+    #[serde(rename = "x")]
+    exchange_id: u8,
+    #[serde(rename = "p")]
+    price: f64,
+    #[serde(rename = "s")]
+    size: u32,
+    #[serde(rename = "c", default = "default_conditions")]
+    conditions: Vec<u8>,
+    #[serde(rename = "t")]
+    timestamp: i64,
+    #[serde(rename = "z")]
+    tape: Tape,
+}
+
+/// CSV rows carry their conditions as a single space separated column, e.g. `"12 37"`.
+#[derive(Deserialize)]
+struct CsvTrade {
+    #[serde(rename = "i")]
+    trade_id: String,
+    #[serde(rename = "x")]
+    exchange_id: u8,
+    #[serde(rename = "p")]
+    price: f64,
+    #[serde(rename = "s")]
+    size: u32,
+    #[serde(rename = "c", default)]
+    conditions: String,
+    #[serde(rename = "t")]
+    timestamp: i64,
+    #[serde(rename = "z")]
+    tape: Tape,
+}
+
+impl FileTrade {
+    fn into_trade(self, symbol: &str) -> Trade {
+        Trade {
+            symbol: symbol.to_string(),
+            trade_id: self.trade_id,
+            exchange_id: self.exchange_id,
+            price: self.price,
+            size: self.size,
+            conditions: self.conditions,
+            timestamp: self.timestamp,
+            tape: self.tape,
+        }
+    }
+}
+
+impl TryFrom<CsvTrade> for FileTrade {
+    type Error = Error;
+
+    /// Fails on a condition code that is not a number, rather than replaying the trade without it.
+    fn try_from(t: CsvTrade) -> Result<Self> {
+        let conditions = t
+            .conditions
+            .split_whitespace()
+            .map(|c| {
+                c.parse()
+                    .map_err(|_| Error::InvalidCondition(c.to_string()))
+            })
+            .collect::<Result<Vec<u8>>>()?;
+        Ok(FileTrade {
+            trade_id: t.trade_id,
+            exchange_id: t.exchange_id,
+            price: t.price,
+            size: t.size,
+            conditions,
+            timestamp: t.timestamp,
+            tape: t.tape,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileFormat {
+    Csv,
+    Jsonl,
+}
+
+impl FileFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "csv" => Some(FileFormat::Csv),
+            "jsonl" => Some(FileFormat::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+/// Replays trades from a local tick archive laid out as `<directory>/<SYMBOL>/<YYYY-MM-DD>.csv`
+/// or `<directory>/<SYMBOL>/<YYYY-MM-DD>.jsonl`, one file per symbol and trading day. Quotes,
+/// when requested, are read from `<directory>/<SYMBOL>/quotes/<YYYY-MM-DD>.jsonl`.
+pub struct FileMarket {
+    directory: PathBuf,
+    subscribers: Subscribers,
+    sources: Vec<BoxedSource>,
+    replay: ReplayHandle,
+}
+
+impl Default for FileMarket {
+    fn default() -> Self {
+        let directory = std::env::var("TICK_DATA_DIR").unwrap_or_else(|_| "data".into());
+        Self::new(directory)
+    }
+}
+
+impl actix::Supervised for FileMarket {}
+
+impl SystemService for FileMarket {}
+
+impl FileMarket {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        FileMarket {
+            directory: directory.into(),
+            subscribers: Subscribers::default(),
+            sources: vec![],
+            replay: ReplayHandle::default(),
+        }
+    }
+
+    /// The file holding `symbol`'s trades on `date`, in whichever supported format it exists.
+    fn session_file(&self, symbol: &str, date: NaiveDate) -> Option<PathBuf> {
+        ["csv", "jsonl"]
+            .iter()
+            .map(|ext| {
+                self.directory
+                    .join(symbol)
+                    .join(format!("{}.{}", date.format("%Y-%m-%d"), ext))
+            })
+            .find(|path| path.exists())
+    }
+
+    /// Lazily reads `symbol`'s trades on each of `dates`, one file after the other.
+    pub(crate) fn trade_source(&self, symbol: &str, dates: &[NaiveDate]) -> BoxedSource {
+        let paths: Vec<PathBuf> = dates
+            .iter()
+            .filter_map(|date| {
+                let path = self.session_file(symbol, *date);
+                if path.is_none() {
+                    warn!("No data for {} on {}", symbol, date);
+                }
+                path
+            })
+            .collect();
+        let symbol = symbol.to_string();
+        let trades = paths
+            .into_iter()
+            .flat_map(move |path| {
+                trace!("Reading file: {:?}", &path);
+                read_file(&path, &symbol)
+                    .unwrap_or_else(|e| -> Records<Trade> { Box::new(std::iter::once(Err(e))) })
+            })
+            .map(|t| t.map(MarketEvent::Trade));
+        Box::new(IterSource::new(trades))
+    }
+
+    /// Lazily reads `symbol`'s quotes on each of `dates`, one file after the other.
+    pub(crate) fn quote_source(&self, symbol: &str, dates: &[NaiveDate]) -> BoxedSource {
+        let paths: Vec<PathBuf> = dates
+            .iter()
+            .filter_map(|date| {
+                let path = quote_file(&self.directory, symbol, *date);
+                if path.exists() {
+                    Some(path)
+                } else {
+                    warn!("No quotes for {} on {}", symbol, date);
+                    None
+                }
+            })
+            .collect();
+        let symbol = symbol.to_string();
+        let quotes = paths
+            .into_iter()
+            .flat_map(move |path| {
+                trace!("Reading file: {:?}", &path);
+                read_quotes(&path, &symbol)
+                    .unwrap_or_else(|e| -> Records<Quote> { Box::new(std::iter::once(Err(e))) })
+            })
+            .map(|q| q.map(MarketEvent::Quote));
+        Box::new(IterSource::new(quotes))
+    }
+}
+
+/// A lazily read file of records.
+pub type Records<T> = Box<dyn Iterator<Item = Result<T>> + Send>;
+
+/// Reads the non-blank lines of a JSONL file one at a time.
+fn read_lines<T: DeserializeOwned + Send + 'static>(reader: BufReader<File>) -> Records<T> {
+    Box::new(
+        reader
+            .lines()
+            .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+            .map(|line| -> Result<T> { Ok(serde_json::from_str(&line?)?) }),
+    )
+}
+
+pub fn quote_file(directory: &Path, symbol: &str, date: NaiveDate) -> PathBuf {
+    directory
+        .join(symbol)
+        .join("quotes")
+        .join(format!("{}.jsonl", date.format("%Y-%m-%d")))
+}
+
+pub fn read_quotes(path: &Path, symbol: &str) -> Result<Records<Quote>> {
+    let symbol = symbol.to_string();
+    let quotes =
+        read_lines::<Quote>(BufReader::new(File::open(path)?)).map(move |quote| -> Result<Quote> {
+            let mut quote = quote?;
+            quote.symbol = symbol.clone();
+            Ok(quote)
+        });
+    Ok(Box::new(quotes))
+}
+
+pub fn load_quotes(path: &Path, symbol: &str) -> Result<Vec<Quote>> {
+    read_quotes(path, symbol)?.collect()
+}
+
+pub fn read_file(path: &Path, symbol: &str) -> Result<Records<Trade>> {
+    let reader = BufReader::new(File::open(path)?);
+    let symbol = symbol.to_string();
+    let trades: Records<Trade> = match FileFormat::from_path(path) {
+        Some(FileFormat::Csv) => Box::new(
+            csv::Reader::from_reader(reader)
+                .into_deserialize::<CsvTrade>()
+                .map(move |t| -> Result<Trade> {
+                    Ok(FileTrade::try_from(t?)?.into_trade(&symbol))
+                }),
+        ),
+        Some(FileFormat::Jsonl) => Box::new(
+            read_lines::<FileTrade>(reader)
+                .map(move |t| -> Result<Trade> { Ok(t?.into_trade(&symbol)) }),
+        ),
+        None => Box::new(std::iter::empty()),
+    };
+    Ok(trades)
+}
+
+pub fn load_file(path: &Path, symbol: &str) -> Result<Vec<Trade>> {
+    read_file(path, symbol)?.collect()
+}
+
+/// Writes `records` to `path` as JSONL.
+pub fn write_file<T: Serialize>(path: &Path, records: &[T]) -> Result<()> {
+    let mut writer = FileWriter::create(path)?;
+    writer.write(records)?;
+    writer.finish()
+}
+
+/// Writes JSONL records as they come in. The data goes to a temporary file that only replaces
+/// `path` once finished, so that an interrupted write never leaves a partial day behind.
+pub struct FileWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl FileWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("jsonl.tmp");
+        Ok(FileWriter {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(File::create(&tmp_path)?),
+            tmp_path,
+        })
+    }
+
+    pub fn write<T: Serialize>(&mut self, records: &[T]) -> Result<()> {
+        for record in records {
+            serde_json::to_writer(&mut self.writer, record)?;
+            self.writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.flush()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetDirectory(pub PathBuf);
+
+impl Actor for FileMarket {
+    type Context = Context<Self>;
+}
+
+impl Handler<SetDirectory> for FileMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetDirectory, _ctx: &mut Context<Self>) {
+        self.directory = msg.0;
+    }
+}
+
+impl Handler<Subscribe> for FileMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _ctx: &mut Context<Self>) {
+        self.subscribers.trades.push(msg.0);
+    }
+}
+
+impl Handler<SubscribeQuotes> for FileMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeQuotes, _ctx: &mut Context<Self>) {
+        self.subscribers.quotes.push(msg.0);
+    }
+}
+
+impl Handler<SubscribeBars> for FileMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeBars, _ctx: &mut Context<Self>) {
+        self.subscribers.bars.push(msg.0);
+    }
+}
+
+impl Handler<Start> for FileMarket {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: Start, _ctx: &mut Context<Self>) -> Self::Result {
+        debug!("Scheduling events");
+        let (handle, fut) = replay::replay(
+            EventMerge::new(std::mem::take(&mut self.sources)),
+            self.subscribers.clone(),
+            msg.0,
+        );
+        self.replay = handle;
+        fut
+    }
+}
+
+impl Handler<Control> for FileMarket {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: Control, _ctx: &mut Context<Self>) -> Self::Result {
+        self.replay.send(msg.0)
+    }
+}
+
+impl Handler<Initialize> for FileMarket {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: Initialize, _ctx: &mut Context<Self>) -> Self::Result {
+        info!("Reading data from {:?}", &self.directory);
+        let dates = msg.sessions.dates();
+        self.subscribers.filter = msg.filter;
+        let mut sources = vec![];
+        for symbol in msg.symbols {
+            // Quote sources go first, so that quotes stay ahead of the trades sharing their
+            // timestamp
+            if msg.quotes {
+                sources.push(self.quote_source(&symbol, &dates));
+            }
+            sources.push(self.trade_source(&symbol, &dates));
+        }
+        self.sources = sources;
+        Ok(())
+    }
+}
+
+impl Market for FileMarket {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    fn fixture(name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_csv() {
+        let path = fixture(
+            "2020-09-18.csv",
+            "t,i,x,p,s,c,z\n\
+             1600416000000000000,1,11,106.5,100,,3\n\
+             1600416000000000001,2,12,106.6,20,12 37,3\n",
+        );
+        let trades = load_file(&path, "AAPL").unwrap();
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].symbol, "AAPL");
+        assert!(trades[0].conditions.is_empty());
+        assert_eq!(trades[1].conditions, vec![12, 37]);
+        assert_eq!(trades[1].size, 20);
+    }
+
+    #[test]
+    fn bad_conditions_fail() {
+        let path = fixture(
+            "2020-09-18.csv",
+            "t,i,x,p,s,c,z
+             1600416000000000000,1,11,106.5,100,12 x,3
+",
+        );
+        assert!(matches!(
+            load_file(&path, "AAPL"),
+            Err(Error::InvalidCondition(c)) if c == "x"
+        ));
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = fixture(
+            "2020-09-18.jsonl",
+            "{\"t\":1600416000000000000,\"i\":\"1\",\"x\":11,\"p\":106.5,\"s\":100,\"c\":[37],\"z\":3}\n",
+        );
+        let trades = load_file(&path, "AAPL").unwrap();
+        let copy = path.with_file_name("2020-09-21.jsonl");
+        write_file(&copy, &trades).unwrap();
+        let reloaded = load_file(&copy, "AAPL").unwrap();
+        assert_eq!(reloaded.len(), 1);
+        assert_eq!(reloaded[0].conditions, vec![37]);
+        assert_eq!(reloaded[0].timestamp, trades[0].timestamp);
+    }
+
+    #[test]
+    fn load_jsonl() {
+        let path = fixture(
+            "2020-09-18.jsonl",
+            "{\"t\":1600416000000000000,\"i\":\"1\",\"x\":11,\"p\":106.5,\"s\":100,\"z\":3}\n\
+             \n\
+             {\"t\":1600416000000000001,\"i\":\"2\",\"x\":12,\"p\":106.6,\"s\":20,\"c\":[12],\"z\":3}\n",
+        );
+        let trades = load_file(&path, "AAPL").unwrap();
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[1].conditions, vec![12]);
+        assert!((trades[1].price - 106.6).abs() < 0.001);
+    }
+}
@@ -0,0 +1,104 @@
+use super::*;
+
+/// Polygon condition codes of prints that do not update the last sale price: average price,
+/// cash, derivatively priced, form T out of sequence, official open and close, next day, price
+/// variation, prior reference price, seller, sold out of sequence, odd lot and contingent trades.
+pub const LAST_SALE_INELIGIBLE: [u8; 17] = [
+    2, 7, 10, 13, 15, 16, 20, 21, 22, 29, 32, 33, 37, 38, 52, 53, 56,
+];
+
+/// Decides which replayed trades reach the market's subscribers.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct TradeFilter {
+    /// Trades carrying any of these condition codes are dropped.
+    pub excluded_conditions: Vec<u8>,
+    /// When set, only trades reported on these tapes are kept.
+    pub tapes: Option<Vec<Tape>>,
+    /// When set, only trades executed on these exchanges are kept.
+    pub exchange_ids: Option<Vec<u8>>,
+}
+
+impl Default for TradeFilter {
+    fn default() -> Self {
+        TradeFilter {
+            excluded_conditions: LAST_SALE_INELIGIBLE.to_vec(),
+            tapes: None,
+            exchange_ids: None,
+        }
+    }
+}
+
+impl TradeFilter {
+    /// A filter that keeps every trade.
+    pub fn none() -> Self {
+        TradeFilter {
+            excluded_conditions: vec![],
+            tapes: None,
+            exchange_ids: None,
+        }
+    }
+
+    pub fn accepts(&self, trade: &Trade) -> bool {
+        !trade
+            .conditions
+            .iter()
+            .any(|c| self.excluded_conditions.contains(c))
+            && self
+                .tapes
+                .as_ref()
+                .map_or(true, |tapes| tapes.contains(&trade.tape))
+            && self
+                .exchange_ids
+                .as_ref()
+                .map_or(true, |ids| ids.contains(&trade.exchange_id))
+    }
+
+    /// Whether `event` should be replayed. Only trades are filtered.
+    pub fn accepts_event(&self, event: &MarketEvent) -> bool {
+        match event {
+            MarketEvent::Trade(trade) => self.accepts(trade),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn trade(conditions: Vec<u8>, exchange_id: u8, tape: Tape) -> Trade {
+        Trade {
+            symbol: "AAPL".into(),
+            trade_id: "1".into(),
+            exchange_id,
+            price: 100.0,
+            size: 100,
+            conditions,
+            timestamp: 0,
+            tape,
+        }
+    }
+
+    #[test]
+    fn drops_ineligible_conditions() {
+        let filter = TradeFilter::default();
+        assert!(filter.accepts(&trade(vec![], 11, Tape::C)));
+        assert!(filter.accepts(&trade(vec![14, 41], 11, Tape::C)));
+        assert!(!filter.accepts(&trade(vec![37], 11, Tape::C)));
+        assert!(!filter.accepts(&trade(vec![14, 2], 11, Tape::C)));
+        assert!(TradeFilter::none().accepts(&trade(vec![37], 11, Tape::C)));
+    }
+
+    #[test]
+    fn restricts_tapes_and_exchanges() {
+        let filter = TradeFilter {
+            tapes: Some(vec![Tape::A]),
+            exchange_ids: Some(vec![11, 12]),
+            ..Default::default()
+        };
+        assert!(filter.accepts(&trade(vec![], 11, Tape::A)));
+        assert!(!filter.accepts(&trade(vec![], 11, Tape::C)));
+        assert!(!filter.accepts(&trade(vec![], 4, Tape::A)));
+    }
+}
@@ -0,0 +1,164 @@
+use super::*;
+use futures::future::LocalBoxFuture;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+
+/// Number of events an iterator backed source reads ahead.
+pub const CHUNK_SIZE: usize = 10_000;
+
+/// A lazily loaded stream of market events in timestamp order, typically one symbol's trades
+/// or quotes.
+pub trait EventSource {
+    /// The next events of the stream in timestamp order, or an empty chunk once it is exhausted.
+    fn next_chunk(&mut self) -> LocalBoxFuture<'_, Result<Vec<MarketEvent>>>;
+}
+
+pub type BoxedSource = Box<dyn EventSource + Send>;
+
+/// Reads a fallible iterator of timestamp ordered events `CHUNK_SIZE` events at a time, and
+/// fails on the first event that goes back in time, which the merge could not replay in order.
+pub struct IterSource<I> {
+    iter: I,
+    last_timestamp: i64,
+}
+
+impl<I> IterSource<I> {
+    pub fn new(iter: I) -> Self {
+        IterSource {
+            iter,
+            last_timestamp: i64::MIN,
+        }
+    }
+}
+
+impl<I> IterSource<I>
+where
+    I: Iterator<Item = Result<MarketEvent>>,
+{
+    fn read_chunk(&mut self) -> Result<Vec<MarketEvent>> {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        for event in self.iter.by_ref().take(CHUNK_SIZE) {
+            let event = event?;
+            if event.timestamp() < self.last_timestamp {
+                return Err(Error::UnsortedData(event.timestamp(), self.last_timestamp));
+            }
+            self.last_timestamp = event.timestamp();
+            chunk.push(event);
+        }
+        Ok(chunk)
+    }
+}
+
+impl<I> EventSource for IterSource<I>
+where
+    I: Iterator<Item = Result<MarketEvent>>,
+{
+    fn next_chunk(&mut self) -> LocalBoxFuture<'_, Result<Vec<MarketEvent>>> {
+        Box::pin(futures::future::ready(self.read_chunk()))
+    }
+}
+
+/// Merges any number of sources into a single timestamp ordered stream, holding at most one
+/// chunk per source in memory. Events sharing a timestamp come out in source order.
+pub struct EventMerge {
+    sources: Vec<(BoxedSource, VecDeque<MarketEvent>)>,
+    heads: BinaryHeap<Reverse<(i64, usize)>>,
+    started: bool,
+}
+
+impl EventMerge {
+    pub fn new(sources: Vec<BoxedSource>) -> Self {
+        EventMerge {
+            sources: sources.into_iter().map(|s| (s, VecDeque::new())).collect(),
+            heads: BinaryHeap::new(),
+            started: false,
+        }
+    }
+
+    /// Makes sure the source at `index` has a buffered event, if it has any left, and queues
+    /// that event for merging.
+    async fn refill(&mut self, index: usize) -> Result<()> {
+        let (source, buffer) = &mut self.sources[index];
+        if buffer.is_empty() {
+            buffer.extend(source.next_chunk().await?);
+        }
+        if let Some(event) = buffer.front() {
+            self.heads.push(Reverse((event.timestamp(), index)));
+        }
+        Ok(())
+    }
+
+    pub async fn next(&mut self) -> Result<Option<MarketEvent>> {
+        if !self.started {
+            self.started = true;
+            for index in 0..self.sources.len() {
+                self.refill(index).await?;
+            }
+        }
+        let index = match self.heads.pop() {
+            Some(Reverse((_, index))) => index,
+            None => return Ok(None),
+        };
+        let event = self.sources[index]
+            .1
+            .pop_front()
+            .expect("Queued sources have a buffered event");
+        self.refill(index).await?;
+        Ok(Some(event))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn trade(symbol: &str, timestamp: i64) -> Result<MarketEvent> {
+        Ok(MarketEvent::Trade(Trade {
+            symbol: symbol.into(),
+            trade_id: timestamp.to_string(),
+            exchange_id: 11,
+            price: 100.0,
+            size: 100,
+            conditions: vec![],
+            timestamp,
+            tape: Tape::C,
+        }))
+    }
+
+    #[actix_rt::test]
+    async fn merges_in_timestamp_order() {
+        let a: BoxedSource = Box::new(IterSource::new(
+            vec![trade("A", 1), trade("A", 3), trade("A", 3)].into_iter(),
+        ));
+        let b: BoxedSource = Box::new(IterSource::new(
+            vec![trade("B", 2), trade("B", 3), trade("B", 5)].into_iter(),
+        ));
+        let empty: BoxedSource = Box::new(IterSource::new(std::iter::empty()));
+        let mut merge = EventMerge::new(vec![a, empty, b]);
+        let mut merged = vec![];
+        while let Some(MarketEvent::Trade(t)) = merge.next().await.unwrap() {
+            merged.push((t.symbol, t.timestamp));
+        }
+        let expected: Vec<(String, i64)> = vec![
+            ("A".into(), 1),
+            ("B".into(), 2),
+            ("A".into(), 3),
+            ("A".into(), 3),
+            ("B".into(), 3),
+            ("B".into(), 5),
+        ];
+        assert_eq!(merged, expected);
+    }
+
+    #[actix_rt::test]
+    async fn unsorted_sources_fail() {
+        let unsorted: BoxedSource = Box::new(IterSource::new(
+            vec![trade("A", 1), trade("A", 3), trade("A", 2)].into_iter(),
+        ));
+        let mut merge = EventMerge::new(vec![unsorted]);
+        match merge.next().await {
+            Err(Error::UnsortedData(2, 3)) => (),
+            other => panic!("Expected an ordering error, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -1,12 +1,26 @@
-use crate::errors::Result;
+use crate::calendar;
+use crate::errors::{Error, Result};
 use actix::prelude::*;
+use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
 use serde_repr::*;
+use std::str::FromStr;
+use tracing::warn;
 
+pub mod bars;
+pub mod file;
+pub mod filter;
+pub mod merge;
 #[cfg(feature = "polygon")]
 pub mod polygon;
+pub mod replay;
+pub mod synthetic;
 
-#[derive(Serialize_repr, Deserialize_repr, Debug, Clone)]
+pub use filter::TradeFilter;
+pub use merge::{BoxedSource, EventMerge, EventSource, IterSource};
+pub use replay::{Control, ReplayCommand, ReplayHandle};
+
+#[derive(Serialize_repr, Deserialize_repr, Debug, Clone, PartialEq)]
 #[repr(u8)]
 pub enum Tape {
     A = 1,
@@ -39,16 +53,288 @@ pub struct Trade {
     pub tape: Tape,
 }
 
+/// National best bid and offer for a symbol.
+#[derive(Serialize, Deserialize, Debug, Clone, Message)]
+#[rtype(result = "()")]
+pub struct Quote {
+    #[serde(rename = "sym", default)]
+    pub symbol: String,
+    #[serde(rename = "p")]
+    pub bid_price: f64,
+    #[serde(rename = "s")]
+    pub bid_size: u32,
+    #[serde(rename = "x")]
+    pub bid_exchange_id: u8,
+    #[serde(rename = "P")]
+    pub ask_price: f64,
+    #[serde(rename = "S")]
+    pub ask_size: u32,
+    #[serde(rename = "X")]
+    pub ask_exchange_id: u8,
+    #[serde(rename = "q", default)]
+    pub sequence: i64,
+    #[serde(rename = "t")]
+    pub timestamp: i64,
+    #[serde(rename = "z")]
+    pub tape: Tape,
+}
+
+/// OHLCV bar, stamped with the time it closes so that it is never replayed before its last trade
+/// happened.
+#[derive(Serialize, Deserialize, Debug, Clone, Message)]
+#[rtype(result = "()")]
+pub struct Bar {
+    #[serde(rename = "sym", default)]
+    pub symbol: String,
+    #[serde(rename = "o")]
+    pub open: f64,
+    #[serde(rename = "h")]
+    pub high: f64,
+    #[serde(rename = "l")]
+    pub low: f64,
+    #[serde(rename = "c")]
+    pub close: f64,
+    #[serde(rename = "v")]
+    pub volume: f64,
+    #[serde(rename = "t")]
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone)]
+pub enum MarketEvent {
+    Trade(Trade),
+    Quote(Quote),
+    Bar(Bar),
+}
+
+impl MarketEvent {
+    pub fn timestamp(&self) -> i64 {
+        match self {
+            MarketEvent::Trade(trade) => trade.timestamp,
+            MarketEvent::Quote(quote) => quote.timestamp,
+            MarketEvent::Bar(bar) => bar.timestamp,
+        }
+    }
+}
+
+impl From<Trade> for MarketEvent {
+    fn from(trade: Trade) -> Self {
+        MarketEvent::Trade(trade)
+    }
+}
+
+impl From<Quote> for MarketEvent {
+    fn from(quote: Quote) -> Self {
+        MarketEvent::Quote(quote)
+    }
+}
+
+/// Everyone a market publishes its events to, and which trades they get to see.
+#[derive(Default, Clone)]
+pub struct Subscribers {
+    pub trades: Vec<Recipient<Trade>>,
+    pub quotes: Vec<Recipient<Quote>>,
+    pub bars: Vec<Recipient<Bar>>,
+    pub filter: TradeFilter,
+}
+
+impl Subscribers {
+    /// Publishes `event` and waits until every subscriber has handled it.
+    pub async fn deliver(&self, event: MarketEvent) {
+        match event {
+            MarketEvent::Trade(trade) => deliver(&self.trades, trade).await,
+            MarketEvent::Quote(quote) => deliver(&self.quotes, quote).await,
+            MarketEvent::Bar(bar) => deliver(&self.bars, bar).await,
+        }
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Subscribe(pub Recipient<Trade>);
 
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeQuotes(pub Recipient<Quote>);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeBars(pub Recipient<Bar>);
+
+/// The trading sessions a market should replay.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sessions {
+    /// Every NYSE trading day between `start` and `end`, both inclusive.
+    Range { start: NaiveDate, end: NaiveDate },
+    List(Vec<NaiveDate>),
+}
+
+impl Sessions {
+    pub fn single(date: NaiveDate) -> Self {
+        Sessions::List(vec![date])
+    }
+
+    /// The session dates in chronological order.
+    pub fn dates(&self) -> Vec<NaiveDate> {
+        match self {
+            Sessions::Range { start, end } => calendar::trading_days(*start, *end),
+            Sessions::List(dates) => {
+                let mut dates = dates.clone();
+                dates.sort_unstable();
+                dates.dedup();
+                dates
+            }
+        }
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "Result<()>")]
-pub struct Initialize(pub Vec<String>);
+pub struct Initialize {
+    pub symbols: Vec<String>,
+    pub sessions: Sessions,
+    /// Whether to replay NBBO quotes alongside trades.
+    pub quotes: bool,
+    /// Which trades to replay.
+    pub filter: TradeFilter,
+}
+
+/// How fast a market replays its events.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Speed {
+    /// Paces the replay against the wall clock, this many times faster than the events
+    /// originally occurred.
+    Multiplier(u64),
+    /// Replays each event as soon as every subscriber has finished handling the previous one,
+    /// so that the same input always produces the same results.
+    Max,
+}
+
+impl FromStr for Speed {
+    type Err = Error;
+
+    /// Parses `max`, or a multiplier such as `60`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "max" => Ok(Speed::Max),
+            _ => s
+                .parse()
+                .map(Speed::Multiplier)
+                .map_err(|_| Error::InvalidSpeed(s.to_string())),
+        }
+    }
+}
 
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Start(pub u64);
+pub struct Start(pub Speed);
 
-pub trait Market: Actor + Handler<Subscribe> + Handler<Initialize> + Handler<Start> {}
+pub trait Market:
+    Actor
+    + Handler<Subscribe>
+    + Handler<SubscribeQuotes>
+    + Handler<SubscribeBars>
+    + Handler<Initialize>
+    + Handler<Start>
+    + Handler<Control>
+{
+}
+
+/// The data sources the simulator can replay from.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarketSource {
+    /// Tick files, see `file::FileMarket`.
+    Files,
+    /// OHLCV bar files, see `bars::BarMarket`.
+    Bars,
+    /// Seeded random trades, see `synthetic::SyntheticMarket`.
+    Synthetic,
+    #[cfg(feature = "polygon")]
+    Polygon,
+}
+
+impl Default for MarketSource {
+    #[cfg(feature = "polygon")]
+    fn default() -> Self {
+        MarketSource::Polygon
+    }
+
+    #[cfg(not(feature = "polygon"))]
+    fn default() -> Self {
+        MarketSource::Files
+    }
+}
+
+impl FromStr for MarketSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "files" => Ok(MarketSource::Files),
+            "bars" => Ok(MarketSource::Bars),
+            "synthetic" => Ok(MarketSource::Synthetic),
+            #[cfg(feature = "polygon")]
+            "polygon" => Ok(MarketSource::Polygon),
+            _ => Err(Error::UnknownMarket(s.to_string())),
+        }
+    }
+}
+
+impl MarketSource {
+    /// The registered market actor replaying from this source.
+    pub fn market(self) -> MarketRecipients {
+        match self {
+            MarketSource::Files => MarketRecipients::new(&file::FileMarket::from_registry()),
+            MarketSource::Bars => MarketRecipients::new(&bars::BarMarket::from_registry()),
+            MarketSource::Synthetic => {
+                MarketRecipients::new(&synthetic::SyntheticMarket::from_registry())
+            }
+            #[cfg(feature = "polygon")]
+            MarketSource::Polygon => {
+                MarketRecipients::new(&polygon::historical::PolygonMarket::from_registry())
+            }
+        }
+    }
+}
+
+/// Every message a `Market` handles, addressed to one market actor, so that callers can drive
+/// whichever market was picked at runtime.
+#[derive(Clone)]
+pub struct MarketRecipients {
+    pub subscribe: Recipient<Subscribe>,
+    pub subscribe_quotes: Recipient<SubscribeQuotes>,
+    pub subscribe_bars: Recipient<SubscribeBars>,
+    pub initialize: Recipient<Initialize>,
+    pub start: Recipient<Start>,
+    pub control: Recipient<Control>,
+}
+
+impl MarketRecipients {
+    pub fn new<M>(addr: &Addr<M>) -> Self
+    where
+        M: Market<Context = Context<M>>,
+    {
+        MarketRecipients {
+            subscribe: addr.clone().recipient(),
+            subscribe_quotes: addr.clone().recipient(),
+            subscribe_bars: addr.clone().recipient(),
+            initialize: addr.clone().recipient(),
+            start: addr.clone().recipient(),
+            control: addr.clone().recipient(),
+        }
+    }
+}
+
+async fn deliver<M>(subscribers: &[Recipient<M>], msg: M)
+where
+    M: Message<Result = ()> + Send + Clone + std::fmt::Debug,
+{
+    for subscr in subscribers {
+        let res = subscr.send(msg.clone()).await;
+        if let Err(e) = res {
+            warn!("Error received when delivering {:?}: {:?}", msg, e)
+        }
+    }
+}
@@ -1,11 +1,12 @@
 use super::*;
 use crate::errors::{Error, Result};
+use futures::future::LocalBoxFuture;
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json;
-use std::cmp::Reverse;
-use tokio::time::{DelayQueue, Duration, Instant};
-use tracing::{debug, info, trace, warn};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, trace};
 
 #[derive(Deserialize, Clone)]
 struct NonTickerTrade {
@@ -25,19 +26,114 @@ struct NonTickerTrade {
     tape: Tape,
 }
 
+impl NonTickerTrade {
+    fn into_trade(self, symbol: &str) -> Trade {
+        Trade {
+            symbol: symbol.to_string(),
+            trade_id: self.trade_id,
+            exchange_id: self.exchange_id,
+            price: self.price,
+            size: self.size,
+            conditions: self.conditions,
+            timestamp: self.timestamp,
+            tape: self.tape,
+        }
+    }
+}
+
+/// A record returned by one of the paginated v2 ticks endpoints.
+trait Tick: DeserializeOwned + Clone + Send + 'static {
+    /// What the record is replayed and cached as.
+    type Record: Serialize + Into<MarketEvent>;
+
+    fn timestamp(&self) -> i64;
+
+    /// Whether `other` is the same record as `self`, for records sharing a timestamp.
+    fn same_as(&self, other: &Self) -> bool;
+
+    fn into_record(self, symbol: &str) -> Self::Record;
+}
+
+impl Tick for NonTickerTrade {
+    type Record = Trade;
+
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    fn same_as(&self, other: &Self) -> bool {
+        self.trade_id == other.trade_id
+    }
+
+    fn into_record(self, symbol: &str) -> Trade {
+        self.into_trade(symbol)
+    }
+}
+
+impl Tick for Quote {
+    type Record = Quote;
+
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    fn same_as(&self, other: &Self) -> bool {
+        self.sequence == other.sequence
+    }
+
+    fn into_record(mut self, symbol: &str) -> Quote {
+        self.symbol = symbol.to_string();
+        self
+    }
+}
+
 #[derive(Deserialize)]
-struct PolygonResponse {
+struct PolygonResponse<T> {
     results_count: i32,
     db_latency: i32,
     success: bool,
     ticker: String,
-    results: Vec<NonTickerTrade>,
+    #[serde(default = "Vec::new")]
+    results: Vec<T>,
 }
 
-#[derive(Default)]
+impl<T> PolygonResponse<T> {
+    fn validate(self) -> Result<Vec<T>> {
+        if !self.success {
+            return Err(Error::Polygon(format!(
+                "request for {} was unsuccessful",
+                self.ticker
+            )));
+        }
+        if self.results_count as usize != self.results.len() {
+            return Err(Error::Polygon(format!(
+                "expected {} results for {} but received {}",
+                self.results_count,
+                self.ticker,
+                self.results.len()
+            )));
+        }
+        Ok(self.results)
+    }
+}
+
+/// Maximum page size accepted by the v2 ticks endpoints.
+const PAGE_LIMIT: usize = 50_000;
+
+/// Upper bound on concurrent downloads, to stay clear of Polygon's rate limits.
+const MAX_CONCURRENT_DOWNLOADS: usize = 8;
+
 pub struct PolygonMarket {
-    subscribers: Vec<Recipient<Trade>>,
-    trades: Vec<Trade>,
+    subscribers: Subscribers,
+    sources: Vec<BoxedSource>,
+    replay: ReplayHandle,
+    cache: Option<PathBuf>,
+}
+
+impl Default for PolygonMarket {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl actix::Supervised for PolygonMarket {}
@@ -47,111 +143,339 @@ impl SystemService for PolygonMarket {}
 impl PolygonMarket {
     pub fn new() -> Self {
         PolygonMarket {
-            subscribers: vec![],
-            trades: vec![],
+            subscribers: Subscribers::default(),
+            sources: vec![],
+            replay: ReplayHandle::default(),
+            cache: std::env::var("POLYGON_CACHE_DIR").ok().map(PathBuf::from),
         }
     }
 
-    async fn download_data(symbol: &str) -> Result<Vec<Trade>> {
+    /// Location of the cached trades for `symbol` on `date`. The cache uses the same layout as
+    /// `FileMarket`, so a cache directory can be replayed offline as is.
+    fn cache_path(cache: &Path, symbol: &str, date: NaiveDate) -> PathBuf {
+        cache.join(symbol).join(format!("{}.jsonl", date))
+    }
+
+    /// Downloads `symbol`'s data on `date` into the cache, unless it is already there.
+    async fn prefetch(symbol: &str, date: NaiveDate, quotes: bool, cache: PathBuf) -> Result<()> {
         let client = Client::new();
-        let url = format!(
-            "https://api.polygon.io/v2/ticks/stocks/trades/{}/2020-09-18?apiKey={}",
-            symbol,
-            std::env::var("POLYGON_KEY")?
+        let path = file::quote_file(&cache, symbol, date);
+        if quotes && !path.exists() {
+            debug!("Caching {} {} quotes", symbol, date);
+            cache_session::<Quote>(&client, "nbbo", symbol, date, &path).await?;
+        }
+        let path = Self::cache_path(&cache, symbol, date);
+        if !path.exists() {
+            debug!("Caching {} {} trades", symbol, date);
+            cache_session::<NonTickerTrade>(&client, "trades", symbol, date, &path).await?;
+        }
+        Ok(())
+    }
+
+    /// The sources replaying `symbols` on `dates`: the cached files once the downloads have
+    /// filled the cache, the API itself otherwise. Quote sources go first, so that quotes stay
+    /// ahead of the trades sharing their timestamp.
+    fn sources(
+        symbols: &[String],
+        dates: &[NaiveDate],
+        quotes: bool,
+        cache: Option<&Path>,
+    ) -> Vec<BoxedSource> {
+        let mut sources = vec![];
+        for symbol in symbols {
+            match cache {
+                Some(cache) => {
+                    let files = file::FileMarket::new(cache);
+                    if quotes {
+                        sources.push(files.quote_source(symbol, dates));
+                    }
+                    sources.push(files.trade_source(symbol, dates));
+                }
+                None => {
+                    if quotes {
+                        let source = PolygonSource::<Quote>::new("nbbo", symbol, dates);
+                        sources.push(Box::new(source) as BoxedSource);
+                    }
+                    let source = PolygonSource::<NonTickerTrade>::new("trades", symbol, dates);
+                    sources.push(Box::new(source));
+                }
+            }
+        }
+        sources
+    }
+}
+
+/// Downloads a session into the cache file at `path`, one page at a time.
+async fn cache_session<T: Tick>(
+    client: &Client,
+    endpoint: &'static str,
+    symbol: &str,
+    date: NaiveDate,
+    path: &Path,
+) -> Result<()> {
+    let mut pages = Pages::<T>::new(endpoint, symbol, date);
+    let mut writer = file::FileWriter::create(path)?;
+    while let Some(page) = pages.next(client).await? {
+        let records: Vec<T::Record> = page.into_iter().map(|t| t.into_record(symbol)).collect();
+        writer.write(&records)?;
+    }
+    writer.finish()
+}
+
+/// Replays one symbol's trades or quotes straight from the API as the replay reaches them, so
+/// that only a single page per source is held in memory.
+struct PolygonSource<T> {
+    client: Client,
+    endpoint: &'static str,
+    symbol: String,
+    dates: VecDeque<NaiveDate>,
+    pages: Option<Pages<T>>,
+}
+
+impl<T: Tick> PolygonSource<T> {
+    fn new(endpoint: &'static str, symbol: &str, dates: &[NaiveDate]) -> Self {
+        PolygonSource {
+            client: Client::new(),
+            endpoint,
+            symbol: symbol.to_string(),
+            dates: dates.iter().copied().collect(),
+            pages: None,
+        }
+    }
+}
+
+impl<T: Tick> EventSource for PolygonSource<T> {
+    fn next_chunk(&mut self) -> LocalBoxFuture<'_, Result<Vec<MarketEvent>>> {
+        Box::pin(async move {
+            loop {
+                let pages = match &mut self.pages {
+                    Some(pages) => pages,
+                    None => {
+                        let date = match self.dates.pop_front() {
+                            Some(date) => date,
+                            None => return Ok(vec![]),
+                        };
+                        self.pages = Some(Pages::new(self.endpoint, &self.symbol, date));
+                        continue;
+                    }
+                };
+                match pages.next(&self.client).await? {
+                    Some(page) if !page.is_empty() => {
+                        let symbol = &self.symbol;
+                        return Ok(page
+                            .into_iter()
+                            .map(|t| t.into_record(symbol).into())
+                            .collect());
+                    }
+                    Some(_) => (),
+                    None => self.pages = None,
+                }
+            }
+        })
+    }
+}
+
+/// Pages through a session of one of the v2 ticks endpoints, following the timestamp cursor
+/// until a short page signals the end of the session.
+struct Pages<T> {
+    endpoint: &'static str,
+    symbol: String,
+    date: NaiveDate,
+    /// The records sharing the last timestamp received, which the next page repeats.
+    boundary: Vec<T>,
+    done: bool,
+}
+
+impl<T: Tick> Pages<T> {
+    fn new(endpoint: &'static str, symbol: &str, date: NaiveDate) -> Self {
+        Pages {
+            endpoint,
+            symbol: symbol.to_string(),
+            date,
+            boundary: vec![],
+            done: false,
+        }
+    }
+
+    /// The new records of the next page, or `None` once the session is complete.
+    async fn next(&mut self, client: &Client) -> Result<Option<Vec<T>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let key = std::env::var("POLYGON_KEY")?;
+        let mut url = format!(
+            "https://api.polygon.io/v2/ticks/stocks/{}/{}/{}?limit={}&apiKey={}",
+            self.endpoint, self.symbol, self.date, PAGE_LIMIT, key
         );
+        if let Some(last) = self.boundary.last() {
+            url.push_str(&format!("&timestamp={}", last.timestamp()));
+        }
         trace!("Making request: {}", &url);
         let req = client.get(&url).send().await?;
         let res = req.text().await?;
-        let res: PolygonResponse = serde_json::from_str(&res)?;
-        Ok(res
-            .results
-            .iter()
-            .cloned()
-            .map(|t| Trade {
-                symbol: symbol.to_string(),
-                trade_id: t.trade_id,
-                exchange_id: t.exchange_id,
-                price: t.price,
-                size: t.size,
-                conditions: t.conditions,
-                timestamp: t.timestamp,
-                tape: t.tape,
-            })
-            .collect())
+        let res: PolygonResponse<T> = serde_json::from_str(&res)?;
+        let (records, done) = take_page(&mut self.boundary, res.validate()?, PAGE_LIMIT)?;
+        debug!(
+            "Received {} new {} for {} {}",
+            records.len(),
+            self.endpoint,
+            self.symbol,
+            self.date
+        );
+        self.done = done;
+        Ok(Some(records))
+    }
+}
+
+/// Takes the records of `page` that were not received yet, and tells whether the session is
+/// complete, which a page shorter than `limit` signals. `boundary` holds the records sharing the
+/// last timestamp received so far, and moves along with the page. A full page without new
+/// records means that more than `limit` records share a timestamp, which the cursor cannot step
+/// past, so the rest of the session would be lost.
+fn take_page<T: Tick>(boundary: &mut Vec<T>, page: Vec<T>, limit: usize) -> Result<(Vec<T>, bool)> {
+    let page_len = page.len();
+    let records = next_page(boundary, page);
+    if page_len >= limit && records.is_empty() {
+        return Err(Error::Polygon(format!(
+            "more than {} records at timestamp {}, the rest of the session cannot be paged",
+            limit,
+            boundary.last().map_or(0, |t| t.timestamp())
+        )));
+    }
+    if let Some(last_timestamp) = records.last().map(|t| t.timestamp()) {
+        boundary.retain(|t| t.timestamp() == last_timestamp);
+        boundary.extend(
+            records
+                .iter()
+                .filter(|t| t.timestamp() == last_timestamp)
+                .cloned(),
+        );
     }
+    Ok((records, page_len < limit))
+}
+
+/// Drops the records of `page` that were already received. Polygon's timestamp cursor is
+/// inclusive, so each page repeats the records sharing the last timestamp of the previous one.
+fn next_page<T: Tick>(received: &[T], page: Vec<T>) -> Vec<T> {
+    let last_timestamp = match received.last() {
+        Some(t) => t.timestamp(),
+        None => return page,
+    };
+    let boundary: Vec<&T> = received
+        .iter()
+        .rev()
+        .take_while(|t| t.timestamp() == last_timestamp)
+        .collect();
+    page.into_iter()
+        .filter(|t| {
+            t.timestamp() > last_timestamp
+                || (t.timestamp() == last_timestamp && !boundary.iter().any(|b| b.same_as(t)))
+        })
+        .collect()
 }
 
 impl Actor for PolygonMarket {
     type Context = Context<Self>;
 }
 
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetCacheDirectory(pub Option<PathBuf>);
+
+impl Handler<SetCacheDirectory> for PolygonMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetCacheDirectory, _ctx: &mut Context<Self>) {
+        self.cache = msg.0;
+    }
+}
+
 impl Handler<Subscribe> for PolygonMarket {
     type Result = ();
 
     fn handle(&mut self, msg: Subscribe, _ctx: &mut Context<Self>) {
-        self.subscribers.push(msg.0);
+        self.subscribers.trades.push(msg.0);
+    }
+}
+
+impl Handler<SubscribeQuotes> for PolygonMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeQuotes, _ctx: &mut Context<Self>) {
+        self.subscribers.quotes.push(msg.0);
+    }
+}
+
+impl Handler<SubscribeBars> for PolygonMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeBars, _ctx: &mut Context<Self>) {
+        self.subscribers.bars.push(msg.0);
     }
 }
 
 impl Handler<Start> for PolygonMarket {
-    type Result = ResponseActFuture<Self, ()>;
+    type Result = ResponseFuture<()>;
 
     fn handle(&mut self, msg: Start, _ctx: &mut Context<Self>) -> Self::Result {
-        debug!("Scheduling trades");
-        let mut stream = DelayQueue::new();
-        if self.trades.is_empty() {
-            return Box::pin(actix::fut::ready(()));
-        }
-        let first_message = self
-            .trades
-            .pop()
-            .expect("Guaranteed to have at least one element");
-        let actual_now = Instant::now();
-        let simulated_now = first_message.timestamp;
-        stream.insert_at(first_message, actual_now + Duration::from_nanos(0));
-        while let Some(message) = self.trades.pop() {
-            log::trace!("Scheduling message: {:?}", &message);
-            let event_time = actual_now
-                + Duration::from_nanos((message.timestamp - simulated_now) as u64 / msg.0);
-            stream.insert_at(message, event_time);
-        }
-        let stream = actix::fut::wrap_stream::<_, Self>(stream);
-        let fut = stream
-            .map(|msg, act, _ctx| {
-                let trade = msg.expect("probably will never error").into_inner();
-                //info!("{:?}", &trade);
-                for subscr in &act.subscribers {
-                    let res = subscr.do_send(trade.clone());
-                    if let Err(e) = res {
-                        warn!("Error received when sending Trade: {:?}", e)
-                    }
-                }
-            })
-            .finish();
-        Box::pin(fut)
+        debug!("Scheduling events");
+        let (handle, fut) = replay::replay(
+            EventMerge::new(std::mem::take(&mut self.sources)),
+            self.subscribers.clone(),
+            msg.0,
+        );
+        self.replay = handle;
+        fut
     }
 }
+
+impl Handler<Control> for PolygonMarket {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: Control, _ctx: &mut Context<Self>) -> Self::Result {
+        self.replay.send(msg.0)
+    }
+}
+
 impl Handler<Initialize> for PolygonMarket {
     type Result = ResponseActFuture<Self, Result<()>>;
 
     fn handle(&mut self, msg: Initialize, _ctx: &mut Context<Self>) -> Self::Result {
-        let fut = async {
-            info!("Downloading data");
-            let trades: Vec<Trade> =
-                futures::future::join_all(msg.0.into_iter().map(|symbol| async move {
-                    PolygonMarket::download_data(&symbol).await.unwrap()
-                }))
-                .await
-                .into_iter()
-                .flatten()
-                .collect();
-            Ok::<Vec<Trade>, Error>(trades)
+        use futures::stream::{self, StreamExt, TryStreamExt};
+
+        let cache = self.cache.clone();
+        let quotes = msg.quotes;
+        let symbols = msg.symbols;
+        let dates = msg.sessions.dates();
+        self.subscribers.filter = msg.filter;
+        // Without a cache every session is downloaded as the replay reaches it. With one, the
+        // sessions are downloaded concurrently up front and then read back from disk.
+        let jobs: Vec<(String, NaiveDate)> = match cache {
+            Some(_) => symbols
+                .iter()
+                .flat_map(|symbol| dates.iter().map(move |date| (symbol.clone(), *date)))
+                .collect(),
+            None => vec![],
+        };
+        let downloads = cache.clone();
+        let fut = async move {
+            if let Some(cache) = downloads {
+                info!("Downloading data into {:?}", &cache);
+                stream::iter(jobs)
+                    .map(|(symbol, date)| {
+                        let cache = cache.clone();
+                        async move { PolygonMarket::prefetch(&symbol, date, quotes, cache).await }
+                    })
+                    .buffered(MAX_CONCURRENT_DOWNLOADS)
+                    .try_collect::<Vec<()>>()
+                    .await?;
+            }
+            Ok::<(), Error>(())
         }
         .into_actor(self)
-        .map(|trades, act, _ctx| {
-            let mut trades = trades?;
-            trades.sort_unstable_by_key(|t| Reverse(t.timestamp));
-            act.trades = trades;
+        .map(move |downloaded, act, _ctx| {
+            downloaded?;
+            act.sources = PolygonMarket::sources(&symbols, &dates, quotes, cache.as_deref());
             Ok(())
         });
         Box::pin(fut)
@@ -159,3 +483,72 @@ impl Handler<Initialize> for PolygonMarket {
 }
 
 impl Market for PolygonMarket {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn trade(trade_id: &str, timestamp: i64) -> NonTickerTrade {
+        NonTickerTrade {
+            trade_id: trade_id.into(),
+            exchange_id: 11,
+            price: 100.0,
+            size: 100,
+            conditions: vec![],
+            timestamp,
+            tape: Tape::C,
+        }
+    }
+
+    #[test]
+    fn next_page_skips_repeated_trades() {
+        let received = vec![trade("1", 1), trade("2", 2), trade("3", 2)];
+        let page = vec![trade("2", 2), trade("3", 2), trade("4", 2), trade("5", 3)];
+        let ids: Vec<String> = next_page(&received, page)
+            .into_iter()
+            .map(|t| t.trade_id)
+            .collect();
+        assert_eq!(ids, vec!["4", "5"]);
+    }
+
+    #[test]
+    fn full_pages_must_make_progress() {
+        let mut boundary = vec![trade("1", 1)];
+        let page = vec![trade("1", 1), trade("2", 2)];
+        let (records, done) = take_page(&mut boundary, page, 2).unwrap();
+        assert!(!done);
+        assert_eq!(records.len(), 1);
+        let (records, done) = take_page(&mut boundary, vec![trade("2", 2)], 2).unwrap();
+        assert!(done);
+        assert!(records.is_empty());
+        // Every record of a full page was already received at the cursor's timestamp
+        let page = vec![trade("2", 2), trade("2", 2)];
+        assert!(take_page(&mut boundary, page, 2).is_err());
+    }
+
+    #[test]
+    fn boundary_follows_the_cursor() {
+        let mut boundary = vec![trade("1", 1)];
+        let page = vec![trade("2", 2), trade("3", 2)];
+        take_page(&mut boundary, page, 2).unwrap();
+        let ids: Vec<&str> = boundary.iter().map(|t| t.trade_id.as_str()).collect();
+        assert_eq!(ids, vec!["2", "3"]);
+        // A page sharing the boundary's timestamp adds to it
+        let page = vec![trade("3", 2), trade("4", 2)];
+        let (records, _) = take_page(&mut boundary, page, 2).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(boundary.len(), 3);
+    }
+
+    #[test]
+    fn validate() {
+        let json = r#"{"results_count": 2, "db_latency": 1, "success": true, "ticker": "AAPL",
+            "results": [{"i": "1", "x": 11, "p": 100.0, "s": 10, "t": 1, "z": 3}]}"#;
+        let res: PolygonResponse<NonTickerTrade> = serde_json::from_str(json).unwrap();
+        assert!(res.validate().is_err());
+
+        let json = r#"{"results_count": 0, "db_latency": 1, "success": false, "ticker": "AAPL"}"#;
+        let res: PolygonResponse<NonTickerTrade> = serde_json::from_str(json).unwrap();
+        assert!(res.validate().is_err());
+    }
+}
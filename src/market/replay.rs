@@ -0,0 +1,231 @@
+use super::*;
+use crate::errors::Error;
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{delay_until, Duration, Instant};
+use tracing::{debug, error, info};
+
+/// Steers a running replay.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ReplayCommand {
+    Pause,
+    Resume,
+    SetSpeed { speed: Speed },
+    /// Replays the next `events` events right away, then pauses.
+    Step { events: usize },
+    /// Replays every event before `timestamp` right away, then pauses.
+    SeekTo { timestamp: DateTime<Utc> },
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<()>")]
+pub struct Control(pub ReplayCommand);
+
+/// The sending half of a replay's command channel, held by the market that started it.
+#[derive(Default)]
+pub struct ReplayHandle(Option<UnboundedSender<ReplayCommand>>);
+
+impl ReplayHandle {
+    pub fn send(&self, command: ReplayCommand) -> Result<()> {
+        self.0
+            .as_ref()
+            .ok_or(Error::ReplayNotRunning)?
+            .send(command)
+            .map_err(|_| Error::ReplayNotRunning)
+    }
+}
+
+/// What the replay should do with its next event.
+#[derive(Debug, PartialEq)]
+enum Next {
+    Deliver,
+    WaitUntil(Instant),
+    WaitForCommand,
+}
+
+struct ReplayState {
+    speed: Speed,
+    paused: bool,
+    steps: usize,
+    seek: Option<i64>,
+    /// Wall clock instant and simulated timestamp that pacing is measured from. Cleared
+    /// whenever the flow of the replay changes, so that it never tries to catch up.
+    anchor: Option<(Instant, i64)>,
+}
+
+impl ReplayState {
+    fn new(speed: Speed) -> Self {
+        ReplayState {
+            speed,
+            paused: false,
+            steps: 0,
+            seek: None,
+            anchor: None,
+        }
+    }
+
+    fn apply(&mut self, command: ReplayCommand) {
+        debug!("Applying replay command: {:?}", &command);
+        match command {
+            ReplayCommand::Pause => self.paused = true,
+            ReplayCommand::Resume => {
+                self.paused = false;
+                self.steps = 0;
+            }
+            ReplayCommand::SetSpeed { speed } => self.speed = speed,
+            ReplayCommand::Step { events } => {
+                self.paused = true;
+                self.steps = events;
+            }
+            ReplayCommand::SeekTo { timestamp } => self.seek = Some(timestamp.timestamp_nanos()),
+        }
+        self.anchor = None;
+    }
+
+    fn next(&mut self, timestamp: i64, now: Instant) -> Next {
+        if let Some(target) = self.seek {
+            if timestamp < target {
+                return Next::Deliver;
+            }
+            info!("Reached seek target, pausing");
+            self.seek = None;
+            self.paused = true;
+            self.steps = 0;
+        }
+        if self.steps > 0 {
+            self.steps -= 1;
+            return Next::Deliver;
+        }
+        if self.paused {
+            return Next::WaitForCommand;
+        }
+        match self.speed {
+            Speed::Max => Next::Deliver,
+            Speed::Multiplier(multiplier) => {
+                let (wall, simulated) = *self.anchor.get_or_insert((now, timestamp));
+                let offset = (timestamp - simulated).max(0) as u64 / multiplier.max(1);
+                let deadline = wall + Duration::from_nanos(offset);
+                if deadline <= now {
+                    Next::Deliver
+                } else {
+                    Next::WaitUntil(deadline)
+                }
+            }
+        }
+    }
+}
+
+/// Replays the merged `events` to `subscribers`. Each event is delivered once every subscriber
+/// has handled the previous one. The returned future completes once the last event has been
+/// delivered, or a source fails to load, and the handle steers the replay until then.
+pub(crate) fn replay(
+    mut events: EventMerge,
+    subscribers: Subscribers,
+    speed: Speed,
+) -> (ReplayHandle, ResponseFuture<()>) {
+    let (sender, mut commands) = unbounded_channel();
+    let fut = async move {
+        let mut state = ReplayState::new(speed);
+        loop {
+            let event = match events.next().await {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Stopping replay, failed to load market data: {}", e);
+                    return;
+                }
+            };
+            // Filtered trades are dropped before they take up any replay time
+            if !subscribers.filter.accepts_event(&event) {
+                continue;
+            }
+            wait_for_turn(&mut state, &mut commands, event.timestamp()).await;
+            subscribers.deliver(event).await;
+        }
+        info!("Replay finished");
+    };
+    (ReplayHandle(Some(sender)), Box::pin(fut))
+}
+
+async fn wait_for_turn(
+    state: &mut ReplayState,
+    commands: &mut UnboundedReceiver<ReplayCommand>,
+    timestamp: i64,
+) {
+    loop {
+        while let Ok(command) = commands.try_recv() {
+            state.apply(command);
+        }
+        match state.next(timestamp, Instant::now()) {
+            Next::Deliver => return,
+            Next::WaitUntil(deadline) => {
+                tokio::select! {
+                    _ = delay_until(deadline) => return,
+                    command = commands.recv() => match command {
+                        Some(command) => state.apply(command),
+                        None => {
+                            delay_until(deadline).await;
+                            return;
+                        }
+                    },
+                }
+            }
+            Next::WaitForCommand => match commands.recv().await {
+                Some(command) => state.apply(command),
+                // Nobody is left to resume the replay
+                None => state.apply(ReplayCommand::Resume),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn step_then_wait() {
+        let mut state = ReplayState::new(Speed::Max);
+        let now = Instant::now();
+        assert_eq!(state.next(1, now), Next::Deliver);
+        state.apply(ReplayCommand::Step { events: 2 });
+        assert_eq!(state.next(2, now), Next::Deliver);
+        assert_eq!(state.next(3, now), Next::Deliver);
+        assert_eq!(state.next(4, now), Next::WaitForCommand);
+        state.apply(ReplayCommand::Resume);
+        assert_eq!(state.next(4, now), Next::Deliver);
+    }
+
+    #[test]
+    fn seek_then_pause() {
+        let mut state = ReplayState::new(Speed::Multiplier(1));
+        let now = Instant::now();
+        let target = Utc.timestamp_nanos(10_000_000_000);
+        state.apply(ReplayCommand::SeekTo { timestamp: target });
+        assert_eq!(state.next(0, now), Next::Deliver);
+        assert_eq!(state.next(9_000_000_000, now), Next::Deliver);
+        assert_eq!(state.next(10_000_000_000, now), Next::WaitForCommand);
+    }
+
+    #[test]
+    fn paces_from_anchor() {
+        let mut state = ReplayState::new(Speed::Multiplier(10));
+        let now = Instant::now();
+        assert_eq!(state.next(0, now), Next::Deliver);
+        assert_eq!(
+            state.next(10_000_000_000, now),
+            Next::WaitUntil(now + Duration::from_secs(1))
+        );
+        state.apply(ReplayCommand::SetSpeed {
+            speed: Speed::Multiplier(100),
+        });
+        let later = now + Duration::from_millis(500);
+        assert_eq!(state.next(10_000_000_000, later), Next::Deliver);
+        assert_eq!(
+            state.next(20_000_000_000, later),
+            Next::WaitUntil(later + Duration::from_millis(100))
+        );
+    }
+}
@@ -0,0 +1,434 @@
+use super::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Exp, StandardNormal};
+use tracing::{debug, info};
+
+/// Length of a trading year in seconds, used to scale the annualized drift and volatility.
+const SECONDS_PER_YEAR: f64 = 252.0 * 6.5 * 3600.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(tag = "distribution", rename_all = "snake_case")]
+pub enum TradeSizes {
+    Fixed { size: u32 },
+    /// Exponentially distributed sizes, rounded up to whole shares.
+    Exponential { mean: f64 },
+}
+
+/// Parameters of the geometric Brownian motion driving the synthetic trades.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SyntheticConfig {
+    pub seed: u64,
+    pub initial_price: f64,
+    /// Annualized drift.
+    pub drift: f64,
+    /// Annualized volatility.
+    pub volatility: f64,
+    /// Mean number of trades per second of the regular session.
+    pub trades_per_second: f64,
+    pub trade_sizes: TradeSizes,
+}
+
+impl Default for SyntheticConfig {
+    fn default() -> Self {
+        SyntheticConfig {
+            seed: 0,
+            initial_price: 100.0,
+            drift: 0.0,
+            volatility: 0.2,
+            trades_per_second: 1.0,
+            trade_sizes: TradeSizes::Exponential { mean: 100.0 },
+        }
+    }
+}
+
+impl SyntheticConfig {
+    /// Checks that the parameters describe a process trades can be generated from.
+    pub fn validate(&self) -> Result<()> {
+        let positive = |x: f64| x.is_finite() && x > 0.0;
+        let invalid = |what: &str| Err(Error::InvalidConfig(format!("synthetic {}", what)));
+        if !positive(self.initial_price) {
+            return invalid("initial_price must be positive");
+        }
+        if !self.drift.is_finite() {
+            return invalid("drift must be finite");
+        }
+        if !self.volatility.is_finite() || self.volatility < 0.0 {
+            return invalid("volatility must not be negative");
+        }
+        if !positive(self.trades_per_second) {
+            return invalid("trades_per_second must be positive");
+        }
+        match self.trade_sizes {
+            TradeSizes::Fixed { size: 0 } => invalid("trade size must be positive"),
+            TradeSizes::Exponential { mean } if !positive(mean) => {
+                invalid("mean trade size must be positive")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Draws the size of each trade.
+#[derive(Clone, Copy)]
+enum SizeSampler {
+    Fixed(u32),
+    Exponential(Exp<f64>),
+}
+
+/// FNV-1a, so that each symbol gets its own stream no matter which other symbols are replayed.
+fn symbol_seed(seed: u64, symbol: &str) -> u64 {
+    symbol.bytes().fold(seed ^ 0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Generates one symbol's trades a session at a time. Prices do not move outside of the
+/// session, so each day opens at the previous day's close.
+pub struct TradeGenerator {
+    config: SyntheticConfig,
+    symbol: String,
+    rng: StdRng,
+    arrivals: Exp<f64>,
+    sizes: SizeSampler,
+    price: f64,
+    count: usize,
+}
+
+impl TradeGenerator {
+    pub fn new(config: &SyntheticConfig, symbol: &str) -> Result<Self> {
+        config.validate()?;
+        let exp = |lambda: f64| {
+            Exp::new(lambda).map_err(|e| Error::InvalidConfig(format!("synthetic: {:?}", e)))
+        };
+        let sizes = match config.trade_sizes {
+            TradeSizes::Fixed { size } => SizeSampler::Fixed(size),
+            TradeSizes::Exponential { mean } => SizeSampler::Exponential(exp(1.0 / mean)?),
+        };
+        Ok(TradeGenerator {
+            config: config.clone(),
+            symbol: symbol.to_string(),
+            rng: StdRng::seed_from_u64(symbol_seed(config.seed, symbol)),
+            arrivals: exp(config.trades_per_second)?,
+            sizes,
+            price: config.initial_price,
+            count: 0,
+        })
+    }
+
+    /// The trades during the regular session of `date`.
+    pub fn session(&mut self, date: NaiveDate) -> Vec<Trade> {
+        let (open, close) = calendar::regular_session(date);
+        let close = close.timestamp_nanos();
+        let mut timestamp = open.timestamp_nanos();
+        let mut trades = vec![];
+        loop {
+            let wait: f64 = self.rng.sample(self.arrivals);
+            timestamp += (wait * 1e9).max(1.0) as i64;
+            if timestamp >= close {
+                break;
+            }
+            let dt = wait / SECONDS_PER_YEAR;
+            let shock: f64 = self.rng.sample(StandardNormal);
+            self.price *= ((self.config.drift - 0.5 * self.config.volatility.powi(2)) * dt
+                + self.config.volatility * dt.sqrt() * shock)
+                .exp();
+            let size = match self.sizes {
+                SizeSampler::Fixed(size) => size,
+                SizeSampler::Exponential(sizes) => {
+                    let size: f64 = self.rng.sample(sizes);
+                    size.ceil().max(1.0) as u32
+                }
+            };
+            trades.push(Trade {
+                symbol: self.symbol.clone(),
+                trade_id: self.count.to_string(),
+                exchange_id: 4,
+                price: (self.price * 100.0).round() / 100.0,
+                size,
+                conditions: vec![],
+                timestamp,
+                tape: Tape::A,
+            });
+            self.count += 1;
+        }
+        trades
+    }
+}
+
+/// Generates the trades of `symbol` during the regular session of each of `dates`.
+pub fn generate_trades(
+    config: &SyntheticConfig,
+    symbol: &str,
+    dates: &[NaiveDate],
+) -> Result<Vec<Trade>> {
+    let mut generator = TradeGenerator::new(config, symbol)?;
+    Ok(dates
+        .iter()
+        .flat_map(|date| generator.session(*date))
+        .collect())
+}
+
+/// Replays trades generated from a seeded geometric Brownian motion, for tests and demos that
+/// must run offline and reproducibly.
+#[derive(Default)]
+pub struct SyntheticMarket {
+    config: SyntheticConfig,
+    subscribers: Subscribers,
+    sources: Vec<BoxedSource>,
+    replay: ReplayHandle,
+}
+
+impl actix::Supervised for SyntheticMarket {}
+
+impl SystemService for SyntheticMarket {}
+
+impl SyntheticMarket {
+    pub fn new(config: SyntheticConfig) -> Self {
+        SyntheticMarket {
+            config,
+            subscribers: Subscribers::default(),
+            sources: vec![],
+            replay: ReplayHandle::default(),
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Configure(pub SyntheticConfig);
+
+impl Actor for SyntheticMarket {
+    type Context = Context<Self>;
+}
+
+impl Handler<Configure> for SyntheticMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: Configure, _ctx: &mut Context<Self>) {
+        self.config = msg.0;
+    }
+}
+
+impl Handler<Subscribe> for SyntheticMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _ctx: &mut Context<Self>) {
+        self.subscribers.trades.push(msg.0);
+    }
+}
+
+impl Handler<SubscribeQuotes> for SyntheticMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeQuotes, _ctx: &mut Context<Self>) {
+        self.subscribers.quotes.push(msg.0);
+    }
+}
+
+impl Handler<SubscribeBars> for SyntheticMarket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeBars, _ctx: &mut Context<Self>) {
+        self.subscribers.bars.push(msg.0);
+    }
+}
+
+impl Handler<Start> for SyntheticMarket {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: Start, _ctx: &mut Context<Self>) -> Self::Result {
+        debug!("Scheduling events");
+        let (handle, fut) = replay::replay(
+            EventMerge::new(std::mem::take(&mut self.sources)),
+            self.subscribers.clone(),
+            msg.0,
+        );
+        self.replay = handle;
+        fut
+    }
+}
+
+impl Handler<Control> for SyntheticMarket {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: Control, _ctx: &mut Context<Self>) -> Self::Result {
+        self.replay.send(msg.0)
+    }
+}
+
+impl Handler<Initialize> for SyntheticMarket {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: Initialize, _ctx: &mut Context<Self>) -> Self::Result {
+        info!("Generating synthetic data");
+        let dates = msg.sessions.dates();
+        self.subscribers.filter = msg.filter.clone();
+        self.sources = msg
+            .symbols
+            .iter()
+            .map(|symbol| {
+                let mut generator = TradeGenerator::new(&self.config, symbol)?;
+                let trades = dates
+                    .clone()
+                    .into_iter()
+                    .flat_map(move |date| generator.session(date))
+                    .map(|t| Ok(MarketEvent::Trade(t)));
+                Ok(Box::new(IterSource::new(trades)) as BoxedSource)
+            })
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+}
+
+impl Market for SyntheticMarket {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::{self, actors::AccountManager, actors::SetCash};
+    use crate::asset::{self, actors::AssetManager, types::Asset};
+    use crate::exchange::{self, Exchange, TransmitOrder};
+    use crate::order::{
+        self,
+        actors::{OrderManager, PostOrder},
+        types::{Order, OrderIntent, OrderStatus},
+    };
+    use crate::position::{self, actors::PositionManager};
+    use chrono::Utc;
+
+    fn config() -> SyntheticConfig {
+        SyntheticConfig {
+            seed: 42,
+            trades_per_second: 0.01,
+            ..Default::default()
+        }
+    }
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd(2020, 9, 18)
+    }
+
+    #[test]
+    fn reproducible() {
+        let first = generate_trades(&config(), "AAPL", &[date()]).unwrap();
+        let second = generate_trades(&config(), "AAPL", &[date()]).unwrap();
+        assert!(!first.is_empty());
+        assert_eq!(first.len(), second.len());
+        assert!(first
+            .iter()
+            .zip(&second)
+            .all(|(a, b)| a.price == b.price && a.size == b.size && a.timestamp == b.timestamp));
+        let other = generate_trades(&config(), "TSLA", &[date()]).unwrap();
+        assert_ne!(first[0].price, other[0].price);
+    }
+
+    #[test]
+    fn within_regular_session() {
+        let (open, close) = calendar::regular_session(date());
+        let trades = generate_trades(&config(), "AAPL", &[date()]).unwrap();
+        assert!(trades.iter().all(|t| t.timestamp > open.timestamp_nanos()
+            && t.timestamp < close.timestamp_nanos()));
+        assert!(trades.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+    }
+
+    #[test]
+    fn invalid_configs_fail() {
+        let invalid = vec![
+            SyntheticConfig {
+                trades_per_second: 0.0,
+                ..config()
+            },
+            SyntheticConfig {
+                volatility: -0.2,
+                ..config()
+            },
+            SyntheticConfig {
+                trade_sizes: TradeSizes::Exponential { mean: -1.0 },
+                ..config()
+            },
+        ];
+        for config in invalid {
+            assert!(matches!(
+                generate_trades(&config, "AAPL", &[date()]),
+                Err(Error::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn pipeline() {
+        let symbols = vec!["AAPL".to_string()];
+        AccountManager::from_registry()
+            .send(SetCash {
+                cash: 100_000.0,
+                time: Utc::now(),
+            })
+            .await
+            .unwrap();
+        AssetManager::from_registry()
+            .send(asset::actors::SetAssets {
+                symbols: symbols.clone(),
+            })
+            .await
+            .unwrap();
+        let assets = symbols.iter().map(|s| Asset::from_symbol(s)).collect();
+        Exchange::from_registry()
+            .send(exchange::SetAssets { assets })
+            .await
+            .unwrap();
+
+        let market = SyntheticMarket::new(config()).start();
+        market
+            .send(Initialize {
+                symbols,
+                sessions: Sessions::single(date()),
+                quotes: false,
+                filter: TradeFilter::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        market
+            .send(Subscribe(Exchange::from_registry().recipient()))
+            .await
+            .unwrap();
+        market
+            .send(Subscribe(PositionManager::from_registry().recipient()))
+            .await
+            .unwrap();
+        market.send(Start(Speed::Max)).await.unwrap();
+
+        let asset = asset::get_asset("AAPL").await.unwrap();
+        let order =
+            Order::from_intent(&OrderIntent::new("AAPL").qty(10), &asset, Utc::now()).unwrap();
+        OrderManager::from_registry()
+            .send(PostOrder {
+                order: order.clone(),
+            })
+            .await
+            .unwrap();
+        let fill = Exchange::from_registry()
+            .send(TransmitOrder(order.clone()))
+            .await
+            .unwrap()
+            .unwrap()
+            .expect("Market orders fill immediately");
+        exchange::update_from_fill(&fill).await.unwrap();
+
+        let last_price = generate_trades(&config(), "AAPL", &[date()])
+            .unwrap()
+            .last()
+            .unwrap()
+            .price;
+        assert_eq!(fill.price, last_price);
+        let order = order::get_order(order.id).await.unwrap();
+        assert_eq!(order.status, OrderStatus::Filled);
+        assert_eq!(order.filled_qty, 10);
+        let position = position::get_position("AAPL".into()).await.unwrap();
+        assert_eq!(position.qty, 10);
+        assert!((position.current_price - last_price).abs() < 1e-9);
+        let account = account::get_account().await.unwrap();
+        assert!((account.cash - (100_000.0 - 10.0 * last_price)).abs() < 1e-6);
+    }
+}
@@ -1,7 +1,8 @@
-use super::types::{Order, OrderStatus};
+use super::types::{Order, OrderStatus, OrderType, Side, TimeInForce};
 use crate::errors::{Error, Result};
 use crate::exchange::TradeFill;
 use actix::prelude::*;
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
 use tracing::debug;
 use uuid::Uuid;
@@ -15,6 +16,54 @@ pub struct OrderManager {
     pub orders: HashMap<Uuid, Order>,
 }
 
+impl OrderManager {
+    /// `order` with its legs as they currently are.
+    fn nested(&self, order: &Order) -> Order {
+        let mut order = order.clone();
+        for leg in order.legs.iter_mut().flatten() {
+            if let Some(current) = self.orders.get(&leg.id) {
+                *leg = current.clone();
+            }
+        }
+        order
+    }
+
+    /// Cancels the open legs of `id`, which was canceled: the held legs it will never release, and
+    /// the stop loss working alongside an OCO order on the same side. Legs on the other side were
+    /// released to protect a fill and stay open.
+    fn cancel_open_legs(&mut self, id: &Uuid, time: DateTime<Utc>) {
+        let (side, legs): (Side, Vec<Uuid>) = match self.orders.get(id) {
+            Some(order) => (
+                order.side.clone(),
+                order.legs.iter().flatten().map(|leg| leg.id).collect(),
+            ),
+            None => return,
+        };
+        for leg in legs {
+            if let Some(leg) = self.orders.get_mut(&leg) {
+                if leg.status == OrderStatus::Held || (leg.is_open() && leg.side == side) {
+                    leg.cancel(time).expect("Open orders should be cancelable");
+                }
+            }
+        }
+    }
+
+    /// Cancels the legs of `id` that are still waiting for it to fill, which they never will.
+    fn cancel_held_legs(&mut self, id: &Uuid, time: DateTime<Utc>) {
+        let legs: Vec<Uuid> = match self.orders.get(id) {
+            Some(order) => order.legs.iter().flatten().map(|leg| leg.id).collect(),
+            None => return,
+        };
+        for leg in legs {
+            if let Some(leg) = self.orders.get_mut(&leg) {
+                if leg.status == OrderStatus::Held {
+                    leg.cancel(time).expect("Held orders should be cancelable");
+                }
+            }
+        }
+    }
+}
+
 impl Actor for OrderManager {
     type Context = Context<Self>;
 }
@@ -31,7 +80,12 @@ impl Handler<GetOrders> for OrderManager {
     type Result = MessageResult<GetOrders>;
 
     fn handle(&mut self, _msg: GetOrders, _ctx: &mut Context<Self>) -> Self::Result {
-        MessageResult(self.orders.clone())
+        MessageResult(
+            self.orders
+                .iter()
+                .map(|(id, order)| (*id, self.nested(order)))
+                .collect(),
+        )
     }
 }
 
@@ -48,7 +102,7 @@ impl Handler<GetOrderByClientOrderId> for OrderManager {
         self.orders
             .values()
             .find(|order| order.client_order_id == msg.client_order_id)
-            .cloned()
+            .map(|order| self.nested(order))
     }
 }
 
@@ -62,7 +116,7 @@ impl Handler<GetOrderById> for OrderManager {
     type Result = Option<Order>;
 
     fn handle(&mut self, msg: GetOrderById, _ctx: &mut Context<Self>) -> Self::Result {
-        self.orders.get(&msg.id).cloned()
+        self.orders.get(&msg.id).map(|order| self.nested(order))
     }
 }
 
@@ -76,21 +130,75 @@ impl Handler<PostOrder> for OrderManager {
     type Result = ();
 
     fn handle(&mut self, msg: PostOrder, _ctx: &mut Context<Self>) -> Self::Result {
+        // Legs are orders of their own, and are nested into their parent when it is read
+        for leg in msg.order.legs.iter().flatten() {
+            self.orders.insert(leg.id, leg.clone());
+        }
         self.orders.insert(msg.order.id, msg.order);
     }
 }
 
+/// Activates a held leg once its parent has filled, or grows it as the parent keeps filling.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ActivateOrder {
+    pub id: Uuid,
+    /// The quantity the parent has filled so far.
+    pub qty: u32,
+    pub time: DateTime<Utc>,
+}
+
+impl Handler<ActivateOrder> for OrderManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: ActivateOrder, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(order) = self.orders.get_mut(&msg.id) {
+            if order.status == OrderStatus::Held {
+                order.status = OrderStatus::New;
+                order.submitted_at = Some(msg.time);
+            }
+            order.qty = msg.qty;
+            order.updated_at = Some(msg.time);
+        }
+    }
+}
+
+/// Marks an order as on its way to the exchange, or as having arrived there.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetInFlight {
+    pub id: Uuid,
+    pub in_flight: bool,
+    pub time: DateTime<Utc>,
+}
+
+impl Handler<SetInFlight> for OrderManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetInFlight, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(order) = self.orders.get_mut(&msg.id) {
+            match (&order.status, msg.in_flight) {
+                (OrderStatus::New, true) => order.status = OrderStatus::PendingNew,
+                (OrderStatus::PendingNew, false) => order.status = OrderStatus::New,
+                _ => return,
+            }
+            order.updated_at = Some(msg.time);
+        }
+    }
+}
+
 impl Handler<TradeFill> for OrderManager {
     type Result = Result<()>;
 
     fn handle(&mut self, msg: TradeFill, _ctx: &mut Context<Self>) -> Self::Result {
         self.orders.entry(msg.order.id).and_modify(|order| {
-            let time = Some(msg.time);
-            order.filled_qty = order.qty;
-            order.updated_at = time;
-            order.filled_at = time;
-            order.filled_avg_price = Some(msg.price);
-            order.status = OrderStatus::Filled;
+            order.fill(msg.qty.abs() as u32, msg.price, msg.time);
+            // IOC orders only ever fill on arrival, so whatever is left is canceled
+            if order.time_in_force == TimeInForce::IOC && order.is_open() {
+                order
+                    .cancel(msg.time)
+                    .expect("Open orders should be cancelable");
+            }
         });
         Ok(())
     }
@@ -98,35 +206,242 @@ impl Handler<TradeFill> for OrderManager {
 
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct CancelOrders;
+pub struct CancelOrders {
+    pub time: DateTime<Utc>,
+}
 
 impl Handler<CancelOrders> for OrderManager {
     type Result = ();
 
-    fn handle(&mut self, _msg: CancelOrders, _ctx: &mut Context<Self>) -> Self::Result {
-        for order in self.orders.values_mut() {
-            match order.status {
-                OrderStatus::Filled | OrderStatus::Expired | OrderStatus::Canceled => (),
-                _ => order
-                    .cancel()
-                    .expect("All other statuses should be cancelable"),
-            }
+    fn handle(&mut self, msg: CancelOrders, _ctx: &mut Context<Self>) -> Self::Result {
+        for order in self.orders.values_mut().filter(|order| order.is_open()) {
+            order
+                .cancel(msg.time)
+                .expect("Open orders should be cancelable");
         }
     }
 }
 
 #[derive(Message)]
 #[rtype(result = "Result<()>")]
-pub struct CancelOrder(pub Uuid);
+pub struct CancelOrder {
+    pub id: Uuid,
+    pub time: DateTime<Utc>,
+}
 
 impl Handler<CancelOrder> for OrderManager {
     type Result = Result<()>;
 
     fn handle(&mut self, msg: CancelOrder, _ctx: &mut Context<Self>) -> Self::Result {
         self.orders
-            .get_mut(&msg.0)
+            .get_mut(&msg.id)
             .ok_or_else(|| Error::NotFound)?
-            .cancel()?;
+            .cancel(msg.time)?;
+        self.cancel_open_legs(&msg.id, msg.time);
         Ok(())
     }
 }
+
+/// Mirrors the stop of a trailing stop order as it moves at the exchange.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UpdateOrderType {
+    pub id: Uuid,
+    pub order_type: OrderType,
+}
+
+impl Handler<UpdateOrderType> for OrderManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateOrderType, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(order) = self.orders.get_mut(&msg.id) {
+            order.order_type = msg.order_type;
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ExpireOrder {
+    pub id: Uuid,
+    pub time: DateTime<Utc>,
+}
+
+impl Handler<ExpireOrder> for OrderManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: ExpireOrder, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(order) = self.orders.get_mut(&msg.id) {
+            order.expire(msg.time);
+        }
+        self.cancel_held_legs(&msg.id, msg.time);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RejectOrder {
+    pub id: Uuid,
+    pub time: DateTime<Utc>,
+}
+
+impl Handler<RejectOrder> for OrderManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: RejectOrder, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(order) = self.orders.get_mut(&msg.id) {
+            order.reject(msg.time);
+        }
+        self.cancel_held_legs(&msg.id, msg.time);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asset::types::Asset;
+    use crate::fees::Fees;
+    use crate::order::types::{OrderClass, OrderIntent, StopLossSpec, TakeProfitSpec};
+
+    #[actix_rt::test]
+    async fn fills_accumulate() {
+        let order = Order::from_intent(
+            &OrderIntent::new("AAPL").qty(10),
+            &Asset::from_symbol("AAPL"),
+            Utc::now(),
+        )
+        .unwrap();
+        let manager = OrderManager::default().start();
+        manager
+            .send(PostOrder {
+                order: order.clone(),
+            })
+            .await
+            .unwrap();
+        for (qty, price) in vec![(4, 100.0), (6, 105.0)] {
+            let fill = TradeFill {
+                time: Utc::now(),
+                qty,
+                price,
+                order: order.clone(),
+                fees: Fees::default(),
+            };
+            manager.send(fill).await.unwrap().unwrap();
+            let order = manager
+                .send(GetOrderById { id: order.id })
+                .await
+                .unwrap()
+                .unwrap();
+            if qty == 4 {
+                assert_eq!(order.status, OrderStatus::PartiallyFilled);
+                assert_eq!(order.filled_avg_price, Some(100.0));
+                assert!(order.filled_at.is_none());
+            } else {
+                assert_eq!(order.status, OrderStatus::Filled);
+                assert_eq!(order.filled_qty, 10);
+                assert!((order.filled_avg_price.unwrap() - 103.0).abs() < 1e-9);
+                assert!(order.filled_at.is_some());
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn legs_are_nested() {
+        let intent = OrderIntent::new("AAPL")
+            .qty(10)
+            .order_class(OrderClass::Bracket {
+                take_profit: TakeProfitSpec { limit_price: 110.0 },
+                stop_loss: StopLossSpec {
+                    stop_price: 90.0,
+                    limit_price: None,
+                },
+            });
+        let order = Order::from_intent(&intent, &Asset::from_symbol("AAPL"), Utc::now()).unwrap();
+        let take_profit = order.held_legs()[0].id;
+        let manager = OrderManager::default().start();
+        manager
+            .send(PostOrder {
+                order: order.clone(),
+            })
+            .await
+            .unwrap();
+        manager
+            .send(ActivateOrder {
+                id: take_profit,
+                qty: 4,
+                time: Utc::now(),
+            })
+            .await
+            .unwrap();
+        let leg = manager
+            .send(GetOrderById { id: take_profit })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(leg.status, OrderStatus::New);
+        assert_eq!(leg.qty, 4);
+        let parent = manager
+            .send(GetOrderById { id: order.id })
+            .await
+            .unwrap()
+            .unwrap();
+        let legs = parent.legs.unwrap();
+        assert_eq!(legs[0].status, OrderStatus::New);
+        assert_eq!(legs[1].status, OrderStatus::Held);
+        manager
+            .send(CancelOrder {
+                id: order.id,
+                time: Utc::now(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let parent = manager
+            .send(GetOrderById { id: order.id })
+            .await
+            .unwrap()
+            .unwrap();
+        let legs = parent.legs.unwrap();
+        assert_eq!(legs[0].status, OrderStatus::New);
+        assert_eq!(legs[1].status, OrderStatus::Canceled);
+    }
+
+    #[actix_rt::test]
+    async fn oco_stop_loss_is_canceled_with_its_order() {
+        let intent = OrderIntent::new("AAPL")
+            .qty(10)
+            .side(Side::Sell)
+            .order_type(OrderType::Limit { limit_price: 110.0 })
+            .order_class(OrderClass::OCO {
+                take_profit: TakeProfitSpec { limit_price: 110.0 },
+                stop_loss: StopLossSpec {
+                    stop_price: 90.0,
+                    limit_price: None,
+                },
+            });
+        let order = Order::from_intent(&intent, &Asset::from_symbol("AAPL"), Utc::now()).unwrap();
+        let manager = OrderManager::default().start();
+        manager
+            .send(PostOrder {
+                order: order.clone(),
+            })
+            .await
+            .unwrap();
+        manager
+            .send(CancelOrder {
+                id: order.id,
+                time: Utc::now(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let stop_loss = manager
+            .send(GetOrderById {
+                id: order.active_legs()[0].id,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stop_loss.status, OrderStatus::Canceled);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::asset;
+use crate::clock;
 use crate::errors::{Error, Result};
 use crate::exchange::{self, Exchange, TransmitOrder};
 use actix::prelude::*;
@@ -6,7 +7,6 @@ use actors::{
     CancelOrder, CancelOrders, GetOrderByClientOrderId, GetOrderById, GetOrders, OrderManager,
     PostOrder,
 };
-use chrono::Utc;
 use std::collections::HashMap;
 use types::{Order, OrderIntent};
 use uuid::Uuid;
@@ -38,13 +38,23 @@ pub async fn get_order_by_client_id(client_id: &str, _nested: bool) -> Result<Or
 }
 
 pub async fn cancel_orders() -> Result<()> {
-    OrderManager::from_registry().send(CancelOrders {}).await?;
+    let time = clock::now().await?;
+    OrderManager::from_registry()
+        .send(CancelOrders { time })
+        .await?;
+    Exchange::from_registry()
+        .send(CancelOrders { time })
+        .await?;
     Ok(())
 }
 
 pub async fn cancel_order(id: Uuid) -> Result<()> {
+    let time = clock::now().await?;
     OrderManager::from_registry()
-        .send(CancelOrder(id))
+        .send(CancelOrder { id, time })
+        .await??;
+    Exchange::from_registry()
+        .send(CancelOrder { id, time })
         .await??;
     Ok(())
 }
@@ -52,11 +62,12 @@ pub async fn cancel_order(id: Uuid) -> Result<()> {
 #[tracing::instrument]
 pub async fn post_order(o: OrderIntent) -> Result<Order> {
     let asset = asset::get_asset(&o.symbol).await?;
-    let mut order: Order = Order::from_intent(&o, &asset);
+    let now = clock::now().await?;
+    let mut order: Order = Order::from_intent(&o, &asset, now)?;
     let o2 = order.clone();
     tokio::spawn(async move {
-        order.submitted_at = Some(Utc::now());
-        order.updated_at = Some(Utc::now());
+        order.submitted_at = Some(now);
+        order.updated_at = Some(now);
         OrderManager::from_registry()
             .send(PostOrder {
                 order: order.clone(),
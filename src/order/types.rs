@@ -24,6 +24,35 @@ pub enum OrderType {
         #[serde(deserialize_with = "from_str", serialize_with = "to_string")]
         stop_price: f64,
     },
+    /// A stop that trails the best price since the order reached the exchange by `trail_price`,
+    /// or by `trail_percent` of it. The best price is called the high-water mark, `hwm`, even
+    /// though it is the lowest price for buy orders.
+    TrailingStop {
+        #[serde(
+            default,
+            deserialize_with = "from_str_optional",
+            serialize_with = "to_string_optional"
+        )]
+        trail_price: Option<f64>,
+        #[serde(
+            default,
+            deserialize_with = "from_str_optional",
+            serialize_with = "to_string_optional"
+        )]
+        trail_percent: Option<f64>,
+        #[serde(
+            default,
+            deserialize_with = "from_str_optional",
+            serialize_with = "to_string_optional"
+        )]
+        hwm: Option<f64>,
+        #[serde(
+            default,
+            deserialize_with = "from_str_optional",
+            serialize_with = "to_string_optional"
+        )]
+        stop_price: Option<f64>,
+    },
 }
 
 impl Default for OrderType {
@@ -106,12 +135,34 @@ pub struct TakeProfitSpec {
     pub limit_price: f64,
 }
 
+impl TakeProfitSpec {
+    pub fn order_type(&self) -> OrderType {
+        OrderType::Limit {
+            limit_price: self.limit_price,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct StopLossSpec {
     pub stop_price: f64,
     pub limit_price: Option<f64>,
 }
 
+impl StopLossSpec {
+    pub fn order_type(&self) -> OrderType {
+        match self.limit_price {
+            Some(limit_price) => OrderType::StopLimit {
+                stop_price: self.stop_price,
+                limit_price,
+            },
+            None => OrderType::Stop {
+                stop_price: self.stop_price,
+            },
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(tag = "order_class", rename_all = "lowercase")]
 pub enum OrderClass {
@@ -131,59 +182,101 @@ pub enum OrderClass {
 }
 
 impl OrderClass {
-    fn to_order(&self, oi: &OrderIntent, a: &Asset) -> Option<Vec<Order>> {
-        match self {
+    /// The legs of the order. Bracket and OTO legs close the position opened by the primary
+    /// order and are held until it fills, while the stop loss leg of an OCO order works right
+    /// away on the same side as the primary order.
+    fn to_order(
+        &self,
+        oi: &OrderIntent,
+        a: &Asset,
+        time: DateTime<Utc>,
+    ) -> Result<Option<Vec<Order>>> {
+        let legs = match self {
             Self::Simple => None,
             Self::Bracket {
-                take_profit: _,
-                stop_loss: _,
-            } => todo!(),
+                take_profit,
+                stop_loss,
+            } => {
+                let ordered = match oi.side {
+                    Side::Buy => take_profit.limit_price > stop_loss.stop_price,
+                    Side::Sell => take_profit.limit_price < stop_loss.stop_price,
+                };
+                if !ordered {
+                    return Err(Error::InvalidOrder(
+                        "bracket take_profit must be on the profitable side of stop_loss".into(),
+                    ));
+                }
+                Some(vec![
+                    leg(oi, a, take_profit.order_type(), OrderStatus::Held, time),
+                    leg(oi, a, stop_loss.order_type(), OrderStatus::Held, time),
+                ])
+            }
             Self::OCO {
-                take_profit: _,
-                stop_loss: _,
-            } => todo!(),
+                take_profit,
+                stop_loss,
+            } => {
+                // The primary order is the take profit leg
+                if oi.order_type != take_profit.order_type() {
+                    return Err(Error::InvalidOrder(
+                        "oco orders must be limit orders at the take_profit limit_price".into(),
+                    ));
+                }
+                // Both legs close a position held on the other side
+                let ordered = match oi.side {
+                    Side::Sell => take_profit.limit_price > stop_loss.stop_price,
+                    Side::Buy => take_profit.limit_price < stop_loss.stop_price,
+                };
+                if !ordered {
+                    return Err(Error::InvalidOrder(
+                        "oco take_profit must be on the profitable side of stop_loss".into(),
+                    ));
+                }
+                let mut stop_loss = leg(oi, a, stop_loss.order_type(), OrderStatus::New, time);
+                stop_loss.side = oi.side.clone();
+                Some(vec![stop_loss])
+            }
             Self::OTO {
                 take_profit,
                 stop_loss,
             } => {
                 let order_type = match (take_profit, stop_loss) {
-                    (Some(TakeProfitSpec { limit_price }), None) => OrderType::Limit {
-                        limit_price: *limit_price,
-                    },
-                    (
-                        None,
-                        Some(StopLossSpec {
-                            stop_price,
-                            limit_price,
-                        }),
-                    ) => match limit_price {
-                        Some(limit_price) => OrderType::StopLimit {
-                            stop_price: *stop_price,
-                            limit_price: *limit_price,
-                        },
-                        None => OrderType::Stop {
-                            stop_price: *stop_price,
-                        },
-                    },
-                    _ => panic!(),
+                    (Some(take_profit), None) => take_profit.order_type(),
+                    (None, Some(stop_loss)) => stop_loss.order_type(),
+                    _ => {
+                        return Err(Error::InvalidOrder(
+                            "oto orders take exactly one of take_profit and stop_loss".into(),
+                        ))
+                    }
                 };
-                Some(vec![Order {
-                    id: Uuid::new_v4(),
-                    client_order_id: Uuid::new_v4().to_string(),
-                    created_at: Some(Utc::now()),
-                    submitted_at: Some(Utc::now()),
-                    asset_id: a.id,
-                    symbol: oi.symbol.clone(),
-                    asset_class: a.class.clone(),
-                    qty: oi.qty,
-                    order_type,
-                    side: oi.side.clone().neg(),
-                    time_in_force: oi.time_in_force.clone(),
-                    status: OrderStatus::Held,
-                    ..Default::default()
-                }])
+                Some(vec![leg(oi, a, order_type, OrderStatus::Held, time)])
             }
-        }
+        };
+        Ok(legs)
+    }
+}
+
+/// A leg on the opposite side of the primary order of `oi`.
+fn leg(
+    oi: &OrderIntent,
+    a: &Asset,
+    order_type: OrderType,
+    status: OrderStatus,
+    time: DateTime<Utc>,
+) -> Order {
+    Order {
+        id: Uuid::new_v4(),
+        client_order_id: Uuid::new_v4().to_string(),
+        created_at: Some(time),
+        submitted_at: Some(time),
+        asset_id: a.id,
+        symbol: oi.symbol.clone(),
+        asset_class: a.class.clone(),
+        qty: oi.qty,
+        order_type,
+        side: oi.side.clone().neg(),
+        time_in_force: oi.time_in_force.clone(),
+        status,
+        ..Default::default()
     }
 }
 
@@ -317,44 +410,153 @@ impl Order {
     //    }
     //}
 
-    pub fn from_intent(oi: &OrderIntent, a: &Asset) -> Order {
+    /// The order placed by `oi` at `time`.
+    pub fn from_intent(oi: &OrderIntent, a: &Asset, time: DateTime<Utc>) -> Result<Order> {
         let client_order_id = match &oi.client_order_id {
             None => Uuid::new_v4().to_hyphenated().to_string(),
             Some(s) => s.into(),
         };
-        let legs = oi.order_class.to_order(oi, a);
+        let legs = oi.order_class.to_order(oi, a, time)?;
+        let mut order_type = oi.order_type.clone();
+        if let OrderType::TrailingStop {
+            trail_price,
+            trail_percent,
+            hwm,
+            stop_price,
+        } = &mut order_type
+        {
+            if trail_price.is_some() == trail_percent.is_some() {
+                return Err(Error::InvalidOrder(
+                    "trailing_stop orders take exactly one of trail_price and trail_percent".into(),
+                ));
+            }
+            // The trail starts from the price when the order reaches the exchange
+            *hwm = None;
+            *stop_price = None;
+        }
 
-        Order {
+        Ok(Order {
             id: Uuid::new_v4(),
             client_order_id,
-            created_at: Some(Utc::now()),
+            created_at: Some(time),
             asset_id: a.id,
             symbol: oi.symbol.clone(),
             asset_class: a.class.clone(),
             qty: oi.qty,
-            order_type: oi.order_type.clone(),
+            order_type,
             side: oi.side.clone(),
             time_in_force: oi.time_in_force.clone(),
             status: OrderStatus::New,
             extended_hours: oi.extended_hours,
             legs,
             ..Default::default()
+        })
+    }
+
+    /// Records the execution of `qty` shares at `price`, averaging the fill price over every
+    /// execution so far.
+    pub fn fill(&mut self, qty: u32, price: f64, time: DateTime<Utc>) {
+        if qty == 0 {
+            return;
+        }
+        let filled_qty = self.filled_qty + qty;
+        let filled_value = self.filled_avg_price.unwrap_or(0.0) * self.filled_qty as f64;
+        self.filled_avg_price = Some((filled_value + price * qty as f64) / filled_qty as f64);
+        self.filled_qty = filled_qty;
+        self.updated_at = Some(time);
+        if self.filled_qty >= self.qty {
+            self.status = OrderStatus::Filled;
+            self.filled_at = Some(time);
+        } else {
+            self.status = OrderStatus::PartiallyFilled;
         }
     }
 
-    pub fn cancel(&mut self) -> Result<()> {
-        match self.status {
-            OrderStatus::Filled | OrderStatus::Expired | OrderStatus::Canceled => {
-                Err(Error::Uncancelable)
-            }
-            _ => {
-                let time = Some(Utc::now());
-                self.status = OrderStatus::Canceled;
-                self.canceled_at = time;
-                self.updated_at = time;
-                Ok(())
+    pub fn cancel(&mut self, time: DateTime<Utc>) -> Result<()> {
+        if !self.is_open() {
+            return Err(Error::Uncancelable);
+        }
+        self.status = OrderStatus::Canceled;
+        self.canceled_at = Some(time);
+        self.updated_at = Some(time);
+        Ok(())
+    }
+
+    /// Expires an order that is still open at the end of its time in force.
+    pub fn expire(&mut self, time: DateTime<Utc>) {
+        if self.is_open() {
+            self.status = OrderStatus::Expired;
+            self.expired_at = Some(time);
+            self.updated_at = Some(time);
+        }
+    }
+
+    pub fn reject(&mut self, time: DateTime<Utc>) {
+        if self.is_open() {
+            self.status = OrderStatus::Rejected;
+            self.failed_at = Some(time);
+            self.updated_at = Some(time);
+        }
+    }
+
+    /// Moves the high-water mark of a trailing stop order to `price` if it is better, and the stop
+    /// price along with it. Returns whether anything moved.
+    pub fn trail(&mut self, price: f64) -> bool {
+        if let OrderType::TrailingStop {
+            trail_price,
+            trail_percent,
+            hwm,
+            stop_price,
+        } = &mut self.order_type
+        {
+            let mark = match (&self.side, *hwm) {
+                (Side::Sell, Some(mark)) => mark.max(price),
+                (Side::Buy, Some(mark)) => mark.min(price),
+                (_, None) => price,
+            };
+            if *hwm == Some(mark) {
+                return false;
             }
+            let offset = trail_price.unwrap_or_else(|| mark * trail_percent.unwrap_or(0.0) / 100.0);
+            *hwm = Some(mark);
+            *stop_price = Some(match self.side {
+                Side::Sell => mark - offset,
+                Side::Buy => mark + offset,
+            });
+            return true;
         }
+        false
+    }
+
+    /// The legs that wait for this order to fill before they are activated.
+    pub fn held_legs(&self) -> Vec<Order> {
+        self.legs
+            .iter()
+            .flatten()
+            .filter(|leg| leg.status == OrderStatus::Held)
+            .cloned()
+            .collect()
+    }
+
+    /// The legs that work alongside this order, one cancelling the other.
+    pub fn active_legs(&self) -> Vec<Order> {
+        self.legs
+            .iter()
+            .flatten()
+            .filter(|leg| leg.status != OrderStatus::Held)
+            .cloned()
+            .collect()
+    }
+
+    /// Whether the order may still fill.
+    pub fn is_open(&self) -> bool {
+        !matches!(
+            self.status,
+            OrderStatus::Filled
+                | OrderStatus::Expired
+                | OrderStatus::Canceled
+                | OrderStatus::Rejected
+        )
     }
 }
 
@@ -399,11 +601,181 @@ mod test {
         let _serialize = serde_json::to_string(&deserialize).unwrap();
     }
 
+    #[test]
+    fn bracket_and_oco_legs() {
+        let asset = Asset::from_symbol("AAPL");
+        let take_profit = TakeProfitSpec { limit_price: 110.0 };
+        let stop_loss = StopLossSpec {
+            stop_price: 90.0,
+            limit_price: None,
+        };
+        let bracket = OrderIntent::new("AAPL")
+            .qty(10)
+            .order_class(OrderClass::Bracket {
+                take_profit: take_profit.clone(),
+                stop_loss: stop_loss.clone(),
+            });
+        let order = Order::from_intent(&bracket, &asset, Utc::now()).unwrap();
+        assert_eq!(order.order_type, OrderType::Market);
+        assert_eq!(order.held_legs().len(), 2);
+        assert!(order.held_legs().iter().all(|leg| leg.side == Side::Sell));
+        assert_eq!(
+            order.held_legs()[1].order_type,
+            OrderType::Stop { stop_price: 90.0 }
+        );
+
+        let oco = OrderIntent::new("AAPL")
+            .qty(10)
+            .side(Side::Sell)
+            .order_type(OrderType::Limit { limit_price: 110.0 })
+            .order_class(OrderClass::OCO {
+                take_profit,
+                stop_loss,
+            });
+        let order = Order::from_intent(&oco, &asset, Utc::now()).unwrap();
+        assert_eq!(order.order_type, OrderType::Limit { limit_price: 110.0 });
+        assert!(order.held_legs().is_empty());
+        assert_eq!(order.active_legs()[0].side, Side::Sell);
+    }
+
+    #[test]
+    fn invalid_brackets_and_ocos() {
+        let asset = Asset::from_symbol("AAPL");
+        let bracket = |side, stop_price| {
+            OrderIntent::new("AAPL")
+                .qty(10)
+                .side(side)
+                .order_class(OrderClass::Bracket {
+                    take_profit: TakeProfitSpec { limit_price: 100.0 },
+                    stop_loss: StopLossSpec {
+                        stop_price,
+                        limit_price: None,
+                    },
+                })
+        };
+        assert!(Order::from_intent(&bracket(Side::Buy, 90.0), &asset, Utc::now()).is_ok());
+        assert!(Order::from_intent(&bracket(Side::Sell, 110.0), &asset, Utc::now()).is_ok());
+        for invalid in vec![bracket(Side::Buy, 110.0), bracket(Side::Sell, 90.0)] {
+            assert!(matches!(
+                Order::from_intent(&invalid, &asset, Utc::now()),
+                Err(Error::InvalidOrder(_))
+            ));
+        }
+        let oco = |side, order_type, stop_price| {
+            OrderIntent::new("AAPL")
+                .qty(10)
+                .side(side)
+                .order_type(order_type)
+                .order_class(OrderClass::OCO {
+                    take_profit: TakeProfitSpec { limit_price: 110.0 },
+                    stop_loss: StopLossSpec {
+                        stop_price,
+                        limit_price: None,
+                    },
+                })
+        };
+        let limit = |limit_price| OrderType::Limit { limit_price };
+        assert!(
+            Order::from_intent(&oco(Side::Sell, limit(110.0), 90.0), &asset, Utc::now()).is_ok()
+        );
+        assert!(
+            Order::from_intent(&oco(Side::Buy, limit(110.0), 120.0), &asset, Utc::now()).is_ok()
+        );
+        for invalid in vec![
+            oco(Side::Sell, OrderType::Market, 90.0),
+            // The primary limit differs from the take profit
+            oco(Side::Sell, limit(120.0), 90.0),
+            // Both legs on the same side of the market
+            oco(Side::Sell, limit(110.0), 120.0),
+            oco(Side::Buy, limit(110.0), 90.0),
+        ] {
+            assert!(matches!(
+                Order::from_intent(&invalid, &asset, Utc::now()),
+                Err(Error::InvalidOrder(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn oto_takes_one_leg() {
+        let a = Asset::from_symbol("AAPL");
+        let oto = |take_profit, stop_loss| {
+            OrderIntent::new("AAPL").order_class(OrderClass::OTO {
+                take_profit,
+                stop_loss,
+            })
+        };
+        let stop_loss = StopLossSpec {
+            stop_price: 90.0,
+            limit_price: Some(89.0),
+        };
+        let order =
+            Order::from_intent(&oto(None, Some(stop_loss.clone())), &a, Utc::now()).unwrap();
+        assert_eq!(
+            order.held_legs()[0].order_type,
+            OrderType::StopLimit {
+                stop_price: 90.0,
+                limit_price: 89.0
+            }
+        );
+        let take_profit = TakeProfitSpec { limit_price: 110.0 };
+        assert!(matches!(
+            Order::from_intent(&oto(Some(take_profit), Some(stop_loss)), &a, Utc::now()),
+            Err(Error::InvalidOrder(_))
+        ));
+        assert!(matches!(
+            Order::from_intent(&oto(None, None), &a, Utc::now()),
+            Err(Error::InvalidOrder(_))
+        ));
+    }
+
+    #[test]
+    fn trailing_stop() {
+        let a = Asset::from_symbol("AAPL");
+        let trailing = |trail_price, trail_percent| {
+            OrderIntent::new("AAPL")
+                .side(Side::Sell)
+                .order_type(OrderType::TrailingStop {
+                    trail_price,
+                    trail_percent,
+                    hwm: Some(1.0),
+                    stop_price: None,
+                })
+        };
+        assert!(Order::from_intent(&trailing(None, None), &a, Utc::now()).is_err());
+        assert!(Order::from_intent(&trailing(Some(1.0), Some(1.0)), &a, Utc::now()).is_err());
+
+        let mut order = Order::from_intent(&trailing(None, Some(5.0)), &a, Utc::now()).unwrap();
+        assert!(order.trail(100.0));
+        assert!(order.trail(110.0));
+        assert!(!order.trail(105.0));
+        assert_eq!(
+            order.order_type,
+            OrderType::TrailingStop {
+                trail_price: None,
+                trail_percent: Some(5.0),
+                hwm: Some(110.0),
+                stop_price: Some(104.5),
+            }
+        );
+
+        let json = r#"{"symbol": "AAPL", "qty": "1", "side": "buy", "type": "trailing_stop",
+            "trail_price": "2.5", "time_in_force": "day", "extended_hours": false,
+            "order_class": "simple"}"#;
+        let intent: OrderIntent = serde_json::from_str(json).unwrap();
+        let mut order = Order::from_intent(&intent, &a, Utc::now()).unwrap();
+        order.trail(100.0);
+        order.trail(98.0);
+        let json = serde_json::to_value(&order).unwrap();
+        assert_eq!(json["hwm"], "98");
+        assert_eq!(json["stop_price"], "100.5");
+    }
+
     #[test]
     fn from_intent() {
         let a: Asset = Asset::from_symbol("TEST");
         let oi: OrderIntent = OrderIntent::new(&a.symbol);
-        let o: Order = Order::from_intent(&oi, &a);
+        let o: Order = Order::from_intent(&oi, &a, Utc::now()).unwrap();
         assert_eq!(o.asset_id, a.id);
     }
 
@@ -417,7 +789,7 @@ mod test {
             .time_in_force(TimeInForce::FOK)
             .extended_hours(true)
             .client_order_id("TEST");
-        let o: Order = Order::from_intent(&oi, &a);
+        let o: Order = Order::from_intent(&oi, &a, Utc::now()).unwrap();
         assert_eq!(o.qty, 100);
         assert_eq!(o.side, Side::Sell);
         assert_eq!(o.order_type, OrderType::Limit { limit_price: 100.0 });
@@ -5,7 +5,7 @@ use crate::asset::{
 };
 use crate::errors::{Error, Result};
 use crate::exchange::TradeFill;
-use crate::market::Trade;
+use crate::market::{Bar, Trade};
 use actix::prelude::*;
 use std::collections::HashMap;
 use tracing::{debug, instrument};
@@ -42,6 +42,16 @@ impl Handler<Trade> for PositionManager {
     }
 }
 
+impl Handler<Bar> for PositionManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: Bar, _ctx: &mut Context<Self>) {
+        if let Some(pos) = self.positions.get_mut(&msg.symbol) {
+            pos.update_with_price(msg.close);
+        }
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "HashMap<String, Position>")]
 pub struct GetPositions;
@@ -0,0 +1,220 @@
+use crate::market::Quote;
+use crate::order::types::Side;
+use serde::Deserialize;
+use std::collections::VecDeque;
+
+/// Decay of the exponentially weighted variance of trade to trade returns.
+const VARIANCE_DECAY: f64 = 0.94;
+
+/// Trailing simulated time over which recent volume is summed.
+const VOLUME_WINDOW_NS: i64 = 5 * 60 * 1_000_000_000;
+
+/// Recent trading activity in one symbol, as seen by the exchange.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolStats {
+    last_price: Option<f64>,
+    variance: f64,
+    trades: VecDeque<(i64, u64)>,
+    volume: u64,
+}
+
+impl SymbolStats {
+    pub fn record(&mut self, timestamp: i64, price: f64, size: u64) {
+        if let Some(last_price) = self.last_price {
+            let ret = (price / last_price).ln();
+            self.variance = VARIANCE_DECAY * self.variance + (1.0 - VARIANCE_DECAY) * ret * ret;
+        }
+        self.last_price = Some(price);
+        self.trades.push_back((timestamp, size));
+        self.volume += size;
+        while let Some((first, size)) = self.trades.front().copied() {
+            if timestamp - first <= VOLUME_WINDOW_NS {
+                break;
+            }
+            self.volume -= size;
+            self.trades.pop_front();
+        }
+    }
+
+    /// Standard deviation of the return between consecutive trades.
+    pub fn volatility(&self) -> f64 {
+        self.variance.sqrt()
+    }
+
+    /// Shares traded over the last five minutes of simulated time.
+    pub fn recent_volume(&self) -> u64 {
+        self.volume
+    }
+}
+
+/// Everything a slippage model may price an execution on.
+pub struct Execution<'a> {
+    pub side: &'a Side,
+    pub qty: u32,
+    /// The price the order would fill at without slippage.
+    pub price: f64,
+    pub quote: Option<&'a Quote>,
+    pub stats: Option<&'a SymbolStats>,
+}
+
+pub trait SlippageModel {
+    /// How far against the order `execution` fills from its undisturbed price, in price units.
+    fn slippage(&self, execution: &Execution) -> f64;
+
+    /// The price `execution` actually fills at.
+    fn fill_price(&self, execution: &Execution) -> f64 {
+        let slippage = self.slippage(execution).max(0.0);
+        match execution.side {
+            Side::Buy => execution.price + slippage,
+            Side::Sell => execution.price - slippage,
+        }
+    }
+}
+
+pub struct NoSlippage;
+
+impl SlippageModel for NoSlippage {
+    fn slippage(&self, _execution: &Execution) -> f64 {
+        0.0
+    }
+}
+
+/// A fixed fraction of the price, in basis points.
+pub struct FixedBps(pub f64);
+
+impl SlippageModel for FixedBps {
+    fn slippage(&self, execution: &Execution) -> f64 {
+        execution.price * self.0 / 10_000.0
+    }
+}
+
+/// A fraction of the quoted spread. Executions without a quote do not slip.
+pub struct SpreadFraction(pub f64);
+
+impl SlippageModel for SpreadFraction {
+    fn slippage(&self, execution: &Execution) -> f64 {
+        execution
+            .quote
+            .map_or(0.0, |q| self.0 * (q.ask_price - q.bid_price).max(0.0))
+    }
+}
+
+/// A multiple of the recent trade to trade volatility.
+pub struct VolatilityScaled(pub f64);
+
+impl SlippageModel for VolatilityScaled {
+    fn slippage(&self, execution: &Execution) -> f64 {
+        execution
+            .stats
+            .map_or(0.0, |s| self.0 * s.volatility() * execution.price)
+    }
+}
+
+/// Square root market impact, `coefficient * sqrt(qty / recent volume)` of the price. Orders in
+/// a symbol without recent volume pay the full coefficient.
+pub struct SquareRootImpact(pub f64);
+
+impl SlippageModel for SquareRootImpact {
+    fn slippage(&self, execution: &Execution) -> f64 {
+        let volume = execution
+            .stats
+            .map_or(0, |s| s.recent_volume())
+            .max(execution.qty as u64);
+        if volume == 0 {
+            return 0.0;
+        }
+        self.0 * (execution.qty as f64 / volume as f64).sqrt() * execution.price
+    }
+}
+
+/// Selects one of the built-in slippage models.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(tag = "model", rename_all = "snake_case")]
+pub enum SlippageConfig {
+    None,
+    FixedBps { bps: f64 },
+    Spread { fraction: f64 },
+    Volatility { multiplier: f64 },
+    SquareRoot { coefficient: f64 },
+}
+
+impl Default for SlippageConfig {
+    fn default() -> Self {
+        SlippageConfig::None
+    }
+}
+
+impl SlippageConfig {
+    pub fn model(&self) -> Box<dyn SlippageModel + Send> {
+        match *self {
+            SlippageConfig::None => Box::new(NoSlippage),
+            SlippageConfig::FixedBps { bps } => Box::new(FixedBps(bps)),
+            SlippageConfig::Spread { fraction } => Box::new(SpreadFraction(fraction)),
+            SlippageConfig::Volatility { multiplier } => Box::new(VolatilityScaled(multiplier)),
+            SlippageConfig::SquareRoot { coefficient } => Box::new(SquareRootImpact(coefficient)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::market::Tape;
+
+    fn execution<'a>(
+        side: &'a Side,
+        quote: Option<&'a Quote>,
+        stats: Option<&'a SymbolStats>,
+    ) -> Execution<'a> {
+        Execution {
+            side,
+            qty: 100,
+            price: 100.0,
+            quote,
+            stats,
+        }
+    }
+
+    #[test]
+    fn fixed_bps() {
+        let model = FixedBps(10.0);
+        assert!((model.fill_price(&execution(&Side::Buy, None, None)) - 100.1).abs() < 1e-9);
+        assert!((model.fill_price(&execution(&Side::Sell, None, None)) - 99.9).abs() < 1e-9);
+    }
+
+    #[test]
+    fn spread() {
+        let quote = Quote {
+            symbol: "AAPL".into(),
+            bid_price: 99.9,
+            bid_size: 100,
+            bid_exchange_id: 11,
+            ask_price: 100.1,
+            ask_size: 100,
+            ask_exchange_id: 12,
+            sequence: 0,
+            timestamp: 0,
+            tape: Tape::C,
+        };
+        let model = SpreadFraction(0.5);
+        let price = model.fill_price(&execution(&Side::Buy, Some(&quote), None));
+        assert!((price - 100.1).abs() < 1e-9);
+        assert_eq!(model.fill_price(&execution(&Side::Buy, None, None)), 100.0);
+    }
+
+    #[test]
+    fn volatility_and_impact() {
+        let mut stats = SymbolStats::default();
+        stats.record(0, 100.0, 300);
+        stats.record(1, 101.0, 300);
+        stats.record(VOLUME_WINDOW_NS + 1, 100.0, 100);
+        assert_eq!(stats.recent_volume(), 400);
+        assert!(stats.volatility() > 0.0);
+
+        let slipped = VolatilityScaled(1.0).fill_price(&execution(&Side::Sell, None, Some(&stats)));
+        assert!((100.0 - slipped - stats.volatility() * 100.0).abs() < 1e-9);
+
+        let impact = SquareRootImpact(0.1).slippage(&execution(&Side::Buy, None, Some(&stats)));
+        assert!((impact - 0.1 * 0.5 * 100.0).abs() < 1e-9);
+    }
+}